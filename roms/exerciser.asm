@@ -51,7 +51,7 @@ isz r3 193
 ; at address 201
 src 5
 rd0
-jcn az 215 ; a=0 not sure how to assemble these conditionals
+jcn az 215 ; a=0
 ldm 8
 src 0
 wmp
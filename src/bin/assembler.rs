@@ -1,16 +1,88 @@
+// Two pass assembler for the 4004 and 4040. Pass 1 works out where
+// everything goes and what the labels are, pass 2 turns each line into
+// bytes.
+//
+// The syntax is the one the szyc e4004 assembler takes, which is what the
+// sources in roms/ were written for. Source is one instruction per line. A
+// line can start with a label, which is anything that isn't an instruction,
+// optionally followed by a colon, and ; starts a comment. Operands are
+// separated by spaces or commas. Numbers are decimal or $hex. Registers can
+// be given as R0-R15 and pairs as P0-P7 or R0R1-R14R15, or just by number.
+// JCN conditions can be named, see condition().
+// Everything apart from the label names in the listing is case insensitive.
+//
+// Directives:
+//   * = N, org N         carry on assembling at N
+//   NAME equ N, NAME = N  NAME stands for N
+//   db N, ..., .byte      bytes
+//   dw N, ..., .word      16 bit words, high byte first
+//   fill COUNT [VALUE]    COUNT bytes of VALUE, 0 if not given
+//   ds COUNT, .res        leave COUNT bytes free
+//   align [N], page       skip to the next multiple of N, 256 if not given
+// Values used to decide where things go have to be known by then. Two
+// things landing on the same address is an error.
+//
+// A listing in the same layout as the ones in roms/ is written next to the
+// ROM, optionally with source line numbers and cycle counts.
 
+extern crate mcs4;
+
+use mcs4::cpu::Model;
+use mcs4::disasm;
+use mcs4::instruction;
+use mcs4::instruction::Operands;
+
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::io::BufReader;
 use std::io::BufRead;
+use std::io::Write;
 use std::fs::File;
+use std::process;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Directive {
+    Origin,
+    Equate,
+    Bytes,
+    Words,
+    Fill,
+    Reserve,
+    Align,
+}
 
-const INSTRUCTIONS: &'static [&'static str] = &[
-    "nop", "jcn", "fim", "src", "fin", "jin", "jun", "jms", "inc", "isz", "add", "sub", "ld",
-    "xch", "bbl", "ldm", "wrm", "wmp", "wrr", "wr0", "wr1", "wr2", "wr3", "sbm", "rdm", "rdr",
-    "adm", "rd0", "rd1", "rd2", "rd3", "clb", "iac", "cmc", "cma", "ral", "rar", "tcc", "dac",
-    "tcs", "stc", "daa", "kbp", "dcl"
+const DIRECTIVES: &[(&str, Directive)] = &[
+    ("org",    Directive::Origin),
+    (".org",   Directive::Origin),
+    ("equ",    Directive::Equate),
+    ("=",      Directive::Equate),
+    ("db",     Directive::Bytes),
+    (".byte",  Directive::Bytes),
+    ("dw",     Directive::Words),
+    (".word",  Directive::Words),
+    ("fill",   Directive::Fill),
+    (".fill",  Directive::Fill),
+    ("ds",     Directive::Reserve),
+    (".res",   Directive::Reserve),
+    ("align",  Directive::Align),
+    (".align", Directive::Align),
+    ("page",   Directive::Align),
 ];
 
+// Other names for the JCN conditions in disasm::CONDITIONS.
+const CONDITION_ALIASES: &[(&str, u8)] = &[
+    ("T0",  0b0001),
+    ("CN",  0b0010),
+    ("T1",  0b1001),
+    ("CZ",  0b1010),
+    ("NZA", 0b1100),
+];
+
+const ROM_SIZE: usize = 4096;
+const PAGE_SIZE: u16 = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum Token {
     Label(String),
     Instruction(String),
@@ -18,20 +90,612 @@ enum Token {
     Comma
 }
 
+#[derive(Debug)]
+struct Error {
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// A source line split up. Operands are kept as tokens until pass 2 when
+// all the labels are known.
+#[derive(Debug)]
+struct Line {
+    number: usize,
+    label: Option<String>,
+    instruction: Option<(&'static str, u8, Operands)>,
+    directive: Option<(&'static str, Directive)>,
+    operands: Vec<Token>,
+}
+
+impl Line {
+    // Instruction or data as it goes in the listing, e.g. "LD  R0" or
+    // "LDM $0A".
+    fn text(&self) -> String {
+        let name = match (self.instruction, self.directive) {
+            (Some((name, _, _)), _) | (_, Some((name, _))) => name,
+            _ => "",
+        };
+        let mut text = format!("{:<3}", name.to_uppercase());
+        for token in &self.operands {
+            match *token {
+                Token::Label(ref label) if register(label).is_some() || pair(label).is_some() ||
+                                           condition(label).is_some() => {
+                    text += &format!(" {}", label.to_uppercase())
+                },
+                Token::Label(ref label) => text += &format!(" {}", label),
+                Token::Number(n) => text += &format!(" ${:02X}", n),
+                _ => {},
+            }
+        }
+        text
+    }
+
+    // Machine cycles to execute. One per word except for FIN which takes
+    // an extra one to fetch the data.
+    fn cycles(&self) -> u16 {
+        match self.instruction {
+            Some(("FIN", _, _)) => 2,
+            _ => self.size(),
+        }
+    }
+
+    fn values(&self) -> Vec<&Token> {
+        self.operands.iter().filter(|x| **x != Token::Comma).collect()
+    }
+
+    fn is(&self, directive: Directive) -> bool {
+        self.directive.is_some_and(|x| x.1 == directive)
+    }
+
+    fn size(&self) -> u16 {
+        match self.instruction {
+            Some((_, _, operands)) => match operands {
+                Operands::PairData | Operands::Address |
+                Operands::RegisterAddress | Operands::ConditionAddress => 2,
+                _ => 1,
+            },
+            None => 0,
+        }
+    }
+}
+
+// What pass 2 made of a line.
+struct Assembled<'a> {
+    line: &'a Line,
+    address: u16,
+    size: u16,
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ListingOptions {
+    line_numbers: bool,
+    cycles: bool,
+}
+
 fn main() {
-    let name = env::args().nth(1).unwrap();
-    let f = File::open(name).unwrap();
-    let mut reader = BufReader::new(f);
+    let mut options = ListingOptions::default();
+    let mut files = Vec::new();
+    for arg in env::args().skip(1) {
+        match &*arg {
+            "--line-numbers" => options.line_numbers = true,
+            "--cycles"       => options.cycles = true,
+            _                => files.push(arg),
+        }
+    }
+
+    let name = files.first().cloned().unwrap_or_else(|| usage());
+    let base = match name.rfind('.') {
+        Some(x) => name[..x].to_string(),
+        None => name.clone(),
+    };
+    let output = files.get(1).cloned().unwrap_or_else(|| format!("{}.rom", base));
+    let listing_name = match output.rfind('.') {
+        Some(x) => format!("{}.lst", &output[..x]),
+        None => format!("{}.lst", output),
+    };
+
+    let source: Vec<String> = File::open(&name)
+        .and_then(|f| BufReader::new(f).lines().collect())
+        .unwrap_or_else(|e| fail(&name, e));
+
+    let lines = parse(&source).unwrap_or_else(|e| fail(&name, e));
+    let assembled = assemble(&lines).unwrap_or_else(|e| fail(&name, e));
+
+    File::create(&output)
+        .and_then(|mut f| f.write_all(&rom(&assembled)))
+        .unwrap_or_else(|e| fail(&output, e));
+    File::create(&listing_name)
+        .and_then(|mut f| f.write_all(listing(&assembled, options).as_bytes()))
+        .unwrap_or_else(|e| fail(&listing_name, e));
+}
+
+fn usage() -> ! {
+    eprintln!("usage: assembler [--line-numbers] [--cycles] SOURCE [ROM]");
+    process::exit(2);
+}
+
+fn fail<E: fmt::Display>(name: &str, error: E) -> ! {
+    eprintln!("{}: {}", name, error);
+    process::exit(1);
+}
+
+fn parse(source: &[String]) -> Result<Vec<Line>, Error> {
+    let mut lines = Vec::new();
+    for (n, text) in source.iter().enumerate() {
+        lines.push(parse_line(n + 1, text)?);
+    }
+    Ok(lines)
+}
+
+fn assemble(lines: &[Line]) -> Result<Vec<Assembled<'_>>, Error> {
+    // pass 1
+    let mut labels = HashMap::new();
+    let mut places = Vec::new();
+    let mut address: u16 = 0;
+    for line in lines {
+        let error = |message| Error { line: line.number, message };
+        let (start, size) = place(line, address, &labels).map_err(error)?;
+        if let Some(ref label) = line.label {
+            if labels.insert(label.to_lowercase(), start).is_some() {
+                return Err(error(format!("'{}' defined twice", label)));
+            }
+        }
+        if line.is(Directive::Equate) {
+            places.push((address, 0));
+            continue;
+        }
+        if start as usize + size as usize > ROM_SIZE {
+            return Err(error("past the end of ROM".to_string()));
+        }
+        places.push((start, size));
+        address = start + size;
+    }
+
+    // pass 2
+    let mut assembled = Vec::new();
+    let mut owner = vec![0; ROM_SIZE]; // line that put something there
+    for (line, &(address, size)) in lines.iter().zip(&places) {
+        let error = |message| Error { line: line.number, message };
+        let bytes = encode(line, address, &labels).map_err(error)?;
+        for a in address..address + size {
+            match owner[a as usize] {
+                0 => owner[a as usize] = line.number,
+                other => return Err(error(format!("{:03X} is already used by line {}", a, other))),
+            }
+        }
+        assembled.push(Assembled { line, address, size, bytes });
+    }
+    Ok(assembled)
+}
+
+// Where `line` goes and how much room it takes when the one before ended
+// at `address`. An equate's place is its value.
+fn place(line: &Line, address: u16, labels: &HashMap<String, u16>) -> Result<(u16, u16), String> {
+    let (name, directive) = match line.directive {
+        Some(x) => x,
+        None => return Ok((address, line.size())),
+    };
+
+    let values = line.values();
+    let (least, most) = match directive {
+        Directive::Origin | Directive::Equate | Directive::Reserve => (1, 1),
+        Directive::Bytes | Directive::Words => (1, usize::MAX),
+        Directive::Fill => (1, 2),
+        Directive::Align => (0, 1),
+    };
+    if values.len() < least || values.len() > most {
+        return Err(format!("wrong number of values for {}", name));
+    }
+    let known = |token: &Token, max: u16| operand(token, labels, |_| None, max).map_err(|e| match *token {
+        Token::Label(ref x) if !labels.contains_key(&x.to_lowercase()) =>
+            format!("{}, it has to be defined before {}", e, name),
+        _ => e,
+    });
+
+    let count = values.len() as u16;
+    Ok(match directive {
+        Directive::Origin => (known(values[0], 0xfff)?, 0),
+        Directive::Equate => {
+            if line.label.is_none() {
+                return Err(format!("{} needs a name", name));
+            }
+            (known(values[0], 0xffff)?, 0)
+        },
+        Directive::Bytes => (address, count),
+        Directive::Words => (address, count * 2),
+        Directive::Fill | Directive::Reserve => (address, known(values[0], ROM_SIZE as u16)?),
+        Directive::Align => {
+            let n = match values.first() {
+                Some(token) => known(token, ROM_SIZE as u16)?,
+                None => PAGE_SIZE,
+            };
+            if n == 0 {
+                return Err(format!("can't {} to 0", name));
+            }
+            (address.div_ceil(n) * n, 0)
+        },
+    })
+}
+
+// Gaps are filled with 0.
+fn rom(assembled: &[Assembled<'_>]) -> Vec<u8> {
+    let mut rom = Vec::new();
+    for a in assembled {
+        let start = a.address as usize;
+        let end = start + a.size as usize;
+        if rom.len() < end {
+            rom.resize(end, 0);
+        }
+        rom[start..start + a.bytes.len()].copy_from_slice(&a.bytes);
+    }
+    rom
+}
+
+//   0000:        FIM P0 $A2      20 A2
+//   0005: DONE
+//   0005:        JUN DONE        40 05
+//
+// Line numbers go at the start of each line and cycles at the end. Data
+// goes 8 bytes to a line. Equates aren't listed and origins and alignment
+// only show their label.
+fn listing(assembled: &[Assembled<'_>], options: ListingOptions) -> String {
+    let mut s = String::from("pass 1: done\npass 2\n\n");
+
+    for a in assembled {
+        if a.line.is(Directive::Equate) {
+            continue;
+        }
+        let number = if options.line_numbers { format!("{:5}  ", a.line.number) } else { String::new() };
+        if let Some(ref label) = a.line.label {
+            s += &format!("{}{:04X}: {}\n", number, a.address, label);
+        }
+        if a.line.instruction.is_some() {
+            let mut line = format!("{}{:04X}:        {:<15} {}", number, a.address, a.line.text(), hex(&a.bytes));
+            if options.cycles {
+                line = format!("{:<46}{}", line, a.line.cycles());
+            }
+            s += &line;
+            s += "\n";
+        } else if a.line.directive.is_some() && !a.line.is(Directive::Align) && !a.line.is(Directive::Origin) {
+            let first = &a.bytes[..a.bytes.len().min(8)];
+            let line = format!("{}{:04X}:        {:<15} {}", number, a.address, a.line.text(), hex(first));
+            s += line.trim_end();
+            s += "\n";
+            for (i, chunk) in a.bytes.chunks(8).enumerate().skip(1) {
+                s += &format!("{}{:04X}:        {:<16}{}\n", number, a.address as usize + i * 8, "", hex(chunk));
+            }
+        }
+    }
+
+    s += "done.\n";
+    s
+}
 
-    for line in reader.lines() {
-        let line =  line.unwrap();
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
+
+fn parse_line(number: usize, text: &str) -> Result<Line, Error> {
+    let text = match text.find(';') {
+        Some(x) => text.split_at(x).0,
+        None    => text
+    };
+
+    let mut tokens = tokenize(text).into_iter().peekable();
+    let mut line = Line { number, label: None, instruction: None, directive: None, operands: Vec::new() };
+
+    if let Some(Token::Label(label)) = tokens.peek() {
+        line.label = Some(label.trim_end_matches(':').to_string());
+    }
+    if line.label.is_some() {
+        tokens.next();
+    }
+
+    match tokens.next() {
+        Some(Token::Instruction(name)) => {
+            line.instruction = instruction::opcode(&name).map(|opcode| {
+                let mnemonic = instruction::mnemonic(Model::I4040, opcode).unwrap();
+                (mnemonic, opcode, instruction::operands(opcode))
+            });
+            line.directive = DIRECTIVES.iter().find(|x| x.0 == name).cloned();
+        },
+        Some(token) => return Err(Error { line: number, message: format!("expected an instruction, found {:?}", token) }),
+        None => {},
+    }
+    line.operands = tokens.collect();
+
+    // "* = N" is another way of writing org
+    if line.label.as_ref().is_some_and(|x| x == "*") && line.is(Directive::Equate) {
+        line.label = None;
+        line.directive = Some(("org", Directive::Origin));
+    }
+    Ok(line)
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for word in text.split_whitespace() {
+        for (i, part) in word.split(',').enumerate() {
+            if i > 0 {
+                tokens.push(Token::Comma);
+            }
+            if part.is_empty() {
+                continue;
+            }
+            tokens.push(token(part));
+        }
+    }
+    tokens
+}
+
+fn token(word: &str) -> Token {
+    let lower = word.to_lowercase();
+    if instruction::opcode(&lower).is_some() || DIRECTIVES.iter().any(|x| x.0 == lower) {
+        return Token::Instruction(lower);
+    }
+    let number = match word.strip_prefix('$') {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
+    };
+    match number {
+        Some(n) => Token::Number(n),
+        None => Token::Label(word.to_string()),
+    }
+}
+
+fn encode(line: &Line, address: u16, labels: &HashMap<String, u16>) -> Result<Vec<u8>, String> {
+    if let Some((_, directive)) = line.directive {
+        return data(line, directive, labels);
+    }
+    let (name, opcode, kind) = match line.instruction {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+
+    let values = line.values();
+    let wanted = match kind {
+        Operands::None => 0,
+        Operands::PairData | Operands::RegisterAddress | Operands::ConditionAddress => 2,
+        _ => 1,
+    };
+    if values.len() != wanted {
+        return Err(format!("{} takes {} operand{}", name, wanted, if wanted == 1 { "" } else { "s" }));
+    }
+
+    let register = |token: &Token| operand(token, labels, register, 15);
+    let pair = |token: &Token| operand(token, labels, pair, 7);
+    let value = |token: &Token, max: u16| operand(token, labels, |_| None, max);
+    // JCN and ISZ can only reach the page the following instruction is in
+    let same_page = |token: &Token| {
+        let target = value(token, 0xfff)?;
+        if target >> 8 != ((address + 2) & 0xfff) >> 8 {
+            return Err(format!("{:03X} is not in the same page", target));
+        }
+        Ok(target & 0xff)
+    };
+
+    let bytes = match kind {
+        Operands::None => vec![opcode],
+        Operands::Register => vec![opcode | register(values[0])? as u8],
+        Operands::Pair => vec![opcode | (pair(values[0])? as u8) << 1],
+        Operands::Data => vec![opcode | value(values[0], 15)? as u8],
+        Operands::PairData => vec![opcode | (pair(values[0])? as u8) << 1, value(values[1], 255)? as u8],
+        Operands::Address => {
+            let target = value(values[0], 0xfff)?;
+            vec![opcode | (target >> 8) as u8, target as u8]
+        },
+        Operands::RegisterAddress => vec![opcode | register(values[0])? as u8, same_page(values[1])? as u8],
+        Operands::ConditionAddress => {
+            let condition = operand(values[0], labels, condition, 15).map_err(|e| match *values[0] {
+                Token::Label(ref x) if x.contains('+') && x.split('+').all(|p| condition_bits(p).is_some()) =>
+                    format!("can't mix plain and inverted conditions in '{}'", x),
+                _ => e,
+            })?;
+            vec![opcode | condition as u8, same_page(values[1])? as u8]
+        },
+    };
+    Ok(bytes)
+}
+
+// Bytes for directives that put something in ROM. How many there are was
+// checked in pass 1.
+fn data(line: &Line, directive: Directive, labels: &HashMap<String, u16>) -> Result<Vec<u8>, String> {
+    let values = line.values();
+    let value = |token: &Token, max: u16| operand(token, labels, |_| None, max);
+
+    let mut bytes = Vec::new();
+    match directive {
+        Directive::Bytes => for token in values {
+            bytes.push(value(token, 0xff)? as u8);
+        },
+        Directive::Words => for token in values {
+            let word = value(token, 0xffff)?;
+            bytes.push((word >> 8) as u8);
+            bytes.push(word as u8);
+        },
+        Directive::Fill => {
+            let fill = match values.get(1) {
+                Some(token) => value(token, 0xff)? as u8,
+                None => 0,
+            };
+            bytes = vec![fill; value(values[0], ROM_SIZE as u16)? as usize];
+        },
+        _ => {},
+    }
+    Ok(bytes)
+}
+
+// A number, a label or a name that `named` knows, like R3 or P1.
+fn operand<F>(token: &Token, labels: &HashMap<String, u16>, named: F, max: u16) -> Result<u16, String>
+    where F: Fn(&str) -> Option<u16>
+{
+    let n = match *token {
+        Token::Number(n) => n,
+        Token::Label(ref name) => {
+            match (named(name), labels.get(&name.to_lowercase())) {
+                (Some(n), _) => n,
+                (_, Some(&address)) => address,
+                _ => return Err(format!("unknown label '{}'", name)),
+            }
+        },
+        ref token => return Err(format!("unexpected {:?}", token)),
+    };
+    if n > max {
+        return Err(format!("{} is too big, the most is {}", n, max));
+    }
+    Ok(n)
+}
+
+// R0-R15
+fn register(name: &str) -> Option<u16> {
+    let lower = name.to_lowercase();
+    lower.strip_prefix('r').and_then(|x| x.parse().ok())
+}
+
+// P0-P7 or the registers in the pair, R0R1-R14R15.
+fn pair(name: &str) -> Option<u16> {
+    let lower = name.to_lowercase();
+    if let Some(n) = lower.strip_prefix('p').and_then(|x| x.parse().ok()) {
+        return Some(n);
+    }
+    let mut registers = lower.splitn(3, 'r').skip(1).map(|x| x.parse::<u16>().ok());
+    match (registers.next(), registers.next()) {
+        (Some(Some(even)), Some(Some(odd))) if even % 2 == 0 && odd == even + 1 => Some(even / 2),
+        _ => None,
+    }
+}
+
+// A JCN condition name or several joined with +. The top bit of the
+// condition inverts the other three, which each jump on something
+// different when set. So joined plain ones jump when any of them holds and
+// inverted ones only when all of them do: AZ+C1 jumps when the accumulator
+// is 0 or carry is 1, AN+C0 when it isn't 0 and carry is 0.
+fn condition(name: &str) -> Option<u16> {
+    let mut bits = Vec::new();
+    for part in name.split('+') {
+        bits.push(condition_bits(part)? as u16);
+    }
+    let inverted = bits[0] & 0b1000;
+    if bits.iter().any(|&x| x & 0b1000 != inverted) {
+        return None;
+    }
+    Some(bits.iter().fold(0, |all, x| all | x))
+}
+
+fn condition_bits(name: &str) -> Option<u8> {
+    disasm::CONDITIONS.iter().chain(CONDITION_ALIASES)
+        .find(|x| x.0.eq_ignore_ascii_case(name))
+        .map(|x| x.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let line = match line.find(';') {
-            Some(x) => line.split_at(x).0,
-            None    => &*line
-        }.trim();
+    fn build(source: &str) -> (Vec<u8>, String) {
+        let source: Vec<String> = source.lines().map(|x| x.to_string()).collect();
+        let lines = parse(&source).unwrap();
+        let assembled = assemble(&lines).unwrap();
+        (rom(&assembled), listing(&assembled, ListingOptions::default()))
+    }
+
+    #[test]
+    fn example_01() {
+        let (rom, listing) = build(include_str!("../../roms/example_01.asm"));
+        assert_eq!(rom, &include_bytes!("../../roms/example_01.rom")[..]);
+        assert_eq!(listing, include_str!("../../roms/example_01.lst"));
+    }
+
+    #[test]
+    fn register_test() {
+        let (rom, listing) = build(include_str!("../../roms/register_test.asm"));
+        assert_eq!(rom, &include_bytes!("../../roms/register_test.rom")[..]);
+        assert_eq!(listing, include_str!("../../roms/register_test.lst"));
+    }
+
+    #[test]
+    fn exerciser() {
+        let (rom, listing) = build(include_str!("../../roms/exerciser.asm"));
+        assert_eq!(rom.len(), 0xf9);
+        assert_eq!(rom[..0x12], [0xe2, 0xcf, 0x2a, 0x41, 0x50, 0xde, 0x50, 0xe5, 0x30,
+                                 0xfe, 0x50, 0xee, 0x50, 0xe5, 0x50, 0xee, 0x50, 0xe5]);
+        assert!(rom[0x12..0xb2].iter().all(|&b| b == 0));
+        assert_eq!(rom[0xcb..0xcd], [0x14, 0xd7]); // jcn az 215, which is ld_mk
+        assert_eq!(rom[0xf6..], [0x00, 0xff, 0x00]);
+        for label in &["00D7: ld_mk", "00DE: ck_idx", "00E7: ck_fin", "00F0: ck_dcl"] {
+            assert!(listing.contains(label), "{}", label);
+        }
+    }
+
+    #[test]
+    fn directives() {
+        let (rom, listing) = build("\
+start   nop
+* = 4
+        db 1, $2
+        .byte 3
+        dw $1234
+value   equ 5
+other = 6
+        fill 2 $ff
+        fill 1
+        ds value
+        ldm other
+        align 4
+        page
+        jun start");
+        assert_eq!(rom[..0x12], [0x00, 0, 0, 0, 0x01, 0x02, 0x03, 0x12, 0x34, 0xff, 0xff, 0x00,
+                                 0, 0, 0, 0, 0, 0xd6]);
+        assert!(rom[0x12..0x100].iter().all(|&b| b == 0));
+        assert_eq!(rom[0x100..], [0x40, 0x00]);
+        assert!(listing.contains("0100:        JUN start       40 00\n"));
+        assert!(!listing.contains("EQU") && !listing.contains(": value\n")); // equates aren't listed
 
-        println!("[{}]", line);
+        assert_eq!(build(" org 2\n nop\n align 2\n.org 5\n ldm 1").0, [0, 0, 0, 0, 0, 0xd1]);
     }
 
+    #[test]
+    fn directive_errors() {
+        assert_eq!(error("* = 2\n nop\n* = 2\n nop"), "line 4: 002 is already used by line 2");
+        assert_eq!(error(" fill 5000"), "line 1: 5000 is too big, the most is 4096");
+        assert_eq!(error(" org later\nlater nop"), "line 1: unknown label 'later', it has to be defined before org");
+        assert_eq!(error("x equ 1\n org x x"), "line 2: wrong number of values for org");
+        assert_eq!(error(" equ 1"), "line 1: equ needs a name");
+        assert_eq!(error(" align 0"), "line 1: can't align to 0");
+    }
+
+    fn error(source: &str) -> String {
+        let source: Vec<String> = source.lines().map(|x| x.to_string()).collect();
+        let lines = parse(&source).unwrap();
+        assemble(&lines).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn conditions() {
+        let jcn = |condition: &str| build(&format!(" jcn {} 0", condition)).0[0];
+        let names = [("AZ", 0x14), ("AN", 0x1c), ("C0", 0x1a), ("C1", 0x12), ("CZ", 0x1a), ("CN", 0x12),
+                     ("TZ", 0x11), ("TN", 0x19), ("T0", 0x11), ("T1", 0x19), ("NZA", 0x1c), ("az", 0x14)];
+        for &(name, opcode) in &names {
+            assert_eq!(jcn(name), opcode, "{}", name);
+        }
+        assert_eq!(jcn("az+c1"), 0x16);
+        assert_eq!(jcn("AZ+C1+TZ"), 0x17);
+        assert_eq!(jcn("AN+C0"), 0x1e);
+        assert_eq!(jcn("5"), 0x15);
+        assert_eq!(jcn("$f"), 0x1f);
+    }
+
+    #[test]
+    fn condition_errors() {
+        assert!(error(" jcn az+an 0").contains("can't mix plain and inverted conditions in 'az+an'"));
+        assert_eq!(error(" jcn az+qq 0"), "line 1: unknown label 'az+qq'");
+        assert_eq!(error(" jcn qq 0"), "line 1: unknown label 'qq'");
+    }
 }
@@ -0,0 +1,46 @@
+extern crate mcs4;
+
+use mcs4::cpu::Model;
+use mcs4::disasm;
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::process;
+
+fn main() {
+    let mut model = Model::I4004;
+    let mut origin = 0;
+    let mut rom_file_name = String::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--4040"   => model = Model::I4040,
+            "--origin" => origin = args.next()
+                              .and_then(|x| u16::from_str_radix(&x, 16).ok())
+                              .filter(|&x| x <= 0xfff)
+                              .unwrap_or_else(|| usage()),
+            _          => rom_file_name = arg,
+        }
+    }
+
+    if rom_file_name.is_empty() {
+        usage();
+    }
+
+    let mut rom = Vec::new();
+    let result = fs::File::open(&rom_file_name).and_then(|mut file| file.read_to_end(&mut rom));
+    if let Err(e) = result {
+        eprintln!("{}: {}", rom_file_name, e);
+        process::exit(1);
+    }
+
+    let instructions = disasm::disassemble(model, &rom, origin);
+    print!("{}", disasm::listing(&instructions));
+}
+
+fn usage() -> ! {
+    eprintln!("usage: disasm [--4040] [--origin HEX] ROM");
+    process::exit(2);
+}
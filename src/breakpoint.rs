@@ -0,0 +1,425 @@
+// Breakpoints and watchpoints
+// Breakpoints stop a run before the instruction at their address executes,
+// optionally only when a condition holds and/or once they have been hit a
+// number of times. Watchpoints stop a run after an instruction reads or
+// writes a RAM char, RAM status char, RAM output port or ROM IO port.
+//
+// Conditions are simple comparisons joined with && and ||, e.g.
+//   acc == 0 && carry == 1
+//   r3 >= 8 || pc == 0x1f0
+// Names are acc, carry, pc, r0-r15, cycles and dcl. Numbers are decimal, or
+// hex with a 0x or $ prefix. && binds tighter than ||.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use cpu::CPU;
+use hardware::Location;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn matches(&self, access: Access) -> bool {
+        *self == Access::ReadWrite || *self == access
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Access::Read      => write!(f, "read"),
+            Access::Write     => write!(f, "write"),
+            Access::ReadWrite => write!(f, "access"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    Accumulator,
+    Carry,
+    ProgramCounter,
+    Register(usize),
+    Cycles,
+    CommandControl,
+    Number(u64),
+}
+
+impl Operand {
+    fn value(&self, cpu: &CPU) -> u64 {
+        match *self {
+            Operand::Accumulator    => cpu.accumulator() as u64,
+            Operand::Carry          => cpu.carry() as u64,
+            Operand::ProgramCounter => cpu.program_counter() as u64,
+            Operand::Register(r)    => cpu.index_register(r) as u64,
+            Operand::Cycles         => cpu.cycles(),
+            Operand::CommandControl => cpu.command_control_register() as u64,
+            Operand::Number(n)      => n,
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Operand, String> {
+        let name = s.to_lowercase();
+        let number = match name.strip_prefix("0x").or_else(|| name.strip_prefix('$')) {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => name.parse().ok(),
+        };
+
+        match &*name {
+            "acc"    => Ok(Operand::Accumulator),
+            "carry"  => Ok(Operand::Carry),
+            "pc"     => Ok(Operand::ProgramCounter),
+            "cycles" => Ok(Operand::Cycles),
+            "dcl"    => Ok(Operand::CommandControl),
+            _ => {
+                if let Some(n) = number {
+                    return Ok(Operand::Number(n));
+                }
+                match name.strip_prefix('r').map(|r| r.parse::<usize>()) {
+                    Some(Ok(r)) if r < 16 => Ok(Operand::Register(r)),
+                    Some(_) => Err(format!("no register '{}'", s)),
+                    None => Err(format!("unknown name '{}'", s)),
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessEqual),
+    (">=", Comparison::GreaterEqual),
+    ("<",  Comparison::Less),
+    (">",  Comparison::Greater),
+];
+
+// Any of a list of all of a list of comparisons.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    terms: Vec<Vec<(Operand, Comparison, Operand)>>,
+}
+
+impl Condition {
+    pub fn holds(&self, cpu: &CPU) -> bool {
+        self.terms.iter().any(|all| all.iter().all(|&(ref lhs, comparison, ref rhs)| {
+            let (a, b) = (lhs.value(cpu), rhs.value(cpu));
+            match comparison {
+                Comparison::Equal        => a == b,
+                Comparison::NotEqual     => a != b,
+                Comparison::Less         => a < b,
+                Comparison::LessEqual    => a <= b,
+                Comparison::Greater      => a > b,
+                Comparison::GreaterEqual => a >= b,
+            }
+        }))
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Condition, String> {
+        let mut terms = Vec::new();
+
+        for any in s.split("||") {
+            let mut all = Vec::new();
+            for comparison in any.split("&&") {
+                all.push(parse_comparison(comparison.trim())?);
+            }
+            terms.push(all);
+        }
+
+        Ok(Condition { source: s.trim().to_string(), terms })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn parse_comparison(s: &str) -> Result<(Operand, Comparison, Operand), String> {
+    for &(symbol, comparison) in &COMPARISONS {
+        if let Some(index) = s.find(symbol) {
+            let lhs = s[..index].trim().parse()?;
+            let rhs = s[index + symbol.len()..].trim().parse()?;
+            return Ok((lhs, comparison, rhs));
+        }
+    }
+    Err(format!("no comparison in '{}'", s))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub condition: Option<Condition>,
+    pub hit_count: u32, // stop on this hit and every one after. 0 or 1 stop every time.
+    pub enabled: bool,
+    hits: u32,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint {
+            address,
+            condition: None,
+            hit_count: 0,
+            enabled: true,
+            hits: 0,
+        }
+    }
+
+    pub fn with_condition(mut self, condition: Condition) -> Breakpoint {
+        self.condition = Some(condition);
+        self
+    }
+
+    pub fn with_hit_count(mut self, hit_count: u32) -> Breakpoint {
+        self.hit_count = hit_count;
+        self
+    }
+
+    // Times the address was reached with the condition holding.
+    pub fn hits(&self) -> u32 {
+        self.hits
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub location: Location,
+    pub access: Access,
+    pub enabled: bool,
+}
+
+impl Watchpoint {
+    pub fn new(location: Location, access: Access) -> Watchpoint {
+        Watchpoint {
+            location,
+            access,
+            enabled: true,
+        }
+    }
+}
+
+// All the breakpoints and watchpoints set on a CPU. Each gets an id when
+// added that is used to remove it again.
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    next_id: usize,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty() && self.watchpoints.is_empty()
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.next_id += 1;
+        self.breakpoints.insert(self.next_id, breakpoint);
+        self.next_id
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.next_id += 1;
+        self.watchpoints.insert(self.next_id, watchpoint);
+        self.next_id
+    }
+
+    // Removes the breakpoint or watchpoint with this id.
+    pub fn remove(&mut self, id: usize) -> bool {
+        self.breakpoints.remove(&id).is_some() || self.watchpoints.remove(&id).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    pub fn breakpoint_mut(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(&id)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints.iter().map(|(&id, b)| (id, b))
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints.iter().map(|(&id, w)| (id, w))
+    }
+
+    // Called with the CPU about to execute the instruction at its pc. Counts
+    // hits and says whether to stop.
+    pub fn check_breakpoints(&mut self, cpu: &CPU) -> bool {
+        let pc = cpu.program_counter();
+        let mut stop = false;
+
+        for breakpoint in self.breakpoints.values_mut() {
+            if !breakpoint.enabled || breakpoint.address != pc {
+                continue;
+            }
+            if breakpoint.condition.as_ref().is_some_and(|c| !c.holds(cpu)) {
+                continue;
+            }
+            breakpoint.hits += 1;
+            stop |= breakpoint.hits >= breakpoint.hit_count;
+        }
+
+        stop
+    }
+
+    // Whether any watchpoint covers this access.
+    pub fn check_watchpoints(&self, location: Location, access: Access) -> bool {
+        self.watchpoints.values().any(|w| {
+            w.enabled && w.location == location && w.access.matches(access)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::{CPU, StopReason};
+    use hardware::Hardware;
+    use super::*;
+
+    // INC R0 then JUN back to it, forever
+    fn counter() -> CPU {
+        CPU::new(Hardware::new(vec![0x60, 0x40, 0x00]).unwrap())
+    }
+
+    fn hits(cpu: &CPU, id: usize) -> u32 {
+        cpu.breakpoints().breakpoints().find(|&(x, _)| x == id).unwrap().1.hits()
+    }
+
+    #[test]
+    fn hit_count() {
+        let mut cpu = counter();
+        let id = cpu.breakpoints_mut().add_breakpoint(Breakpoint::new(0).with_hit_count(3));
+
+        assert_eq!(cpu.run_for(1000), StopReason::Breakpoint(0));
+        assert_eq!(cpu.index_register(0), 3);
+        assert_eq!(hits(&cpu, id), 3);
+
+        // every hit after that stops
+        assert_eq!(cpu.run_for(1000), StopReason::Breakpoint(0));
+        assert_eq!(cpu.index_register(0), 4);
+    }
+
+    #[test]
+    fn condition() {
+        let mut cpu = counter();
+        let condition = "r0 == 5 || acc != 0".parse().unwrap();
+        let id = cpu.breakpoints_mut().add_breakpoint(Breakpoint::new(0).with_condition(condition));
+
+        assert_eq!(cpu.run_for(1000), StopReason::Breakpoint(0));
+        assert_eq!(cpu.index_register(0), 5);
+        assert_eq!(hits(&cpu, id), 1);
+    }
+
+    #[test]
+    fn disabled_and_removed() {
+        let mut cpu = counter();
+        let id = cpu.breakpoints_mut().add_breakpoint(Breakpoint::new(0));
+        cpu.breakpoints_mut().breakpoint_mut(id).unwrap().enabled = false;
+        assert_eq!(cpu.run_for(20), StopReason::CycleBudget);
+        assert!(cpu.breakpoints_mut().remove(id));
+        assert!(cpu.breakpoints().is_empty());
+    }
+
+    #[test]
+    fn parse_conditions() {
+        let condition: Condition = "acc == 0 && carry == 1 || r3 >= $8".parse().unwrap();
+        assert_eq!(condition.terms.len(), 2);
+        assert_eq!(condition.terms[0].len(), 2);
+        assert_eq!(condition.terms[1][0], (Operand::Register(3), Comparison::GreaterEqual, Operand::Number(8)));
+
+        assert!("r16 == 1".parse::<Condition>().is_err());
+        assert!("pc".parse::<Condition>().is_err());
+        assert!("foo < 2".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn condition_holds() {
+        let mut cpu = counter();
+        cpu.run_for(6); // INC R0 twice
+        let holds = |s: &str| s.parse::<Condition>().unwrap().holds(&cpu);
+        assert!(holds("r0 == 2"));
+        assert!(holds("r0 > 1 && pc == 0"));
+        assert!(!holds("r0 < 2"));
+        assert!(holds("r0 < 2 || cycles >= 6"));
+    }
+
+    const CHAR: Location = Location::RamChar { bank: 0, chip: 0, register: 1, character: 2 };
+
+    // FIM P0 $12, SRC P0, LDM 5, WRM, RDM, JUN 005
+    fn ram_access() -> CPU {
+        CPU::new(Hardware::new(vec![0x20, 0x12, 0x21, 0xd5, 0xe0, 0xe9, 0x40, 0x05]).unwrap())
+    }
+
+    #[test]
+    fn write_watchpoint() {
+        let mut cpu = ram_access();
+        cpu.breakpoints_mut().add_watchpoint(Watchpoint::new(CHAR, Access::Write));
+        assert_eq!(cpu.run_for(1000), StopReason::Watchpoint(CHAR, Access::Write));
+        assert_eq!(cpu.program_counter(), 5); // just after the WRM
+        assert_eq!(cpu.run_for(1000), StopReason::CycleBudget); // RDM doesn't count
+    }
+
+    #[test]
+    fn read_watchpoint() {
+        let mut cpu = ram_access();
+        let id = cpu.breakpoints_mut().add_watchpoint(Watchpoint::new(CHAR, Access::Read));
+        assert_eq!(cpu.run_for(1000), StopReason::Watchpoint(CHAR, Access::Read));
+        assert_eq!((cpu.program_counter(), cpu.accumulator()), (6, 5));
+        assert_eq!(cpu.run_for(1000), StopReason::Watchpoint(CHAR, Access::Read));
+
+        assert!(cpu.breakpoints_mut().remove(id));
+        assert_eq!(cpu.run_for(1000), StopReason::CycleBudget);
+    }
+
+    #[test]
+    fn check_watchpoints() {
+        let mut breakpoints = Breakpoints::new();
+        let other = Location::RamChar { bank: 0, chip: 0, register: 1, character: 3 };
+        let port = Location::RomPort { bank: 0, chip: 2 };
+        breakpoints.add_watchpoint(Watchpoint::new(CHAR, Access::ReadWrite));
+        breakpoints.add_watchpoint(Watchpoint::new(port, Access::Read));
+        let mut off = Watchpoint::new(other, Access::ReadWrite);
+        off.enabled = false;
+        breakpoints.add_watchpoint(off);
+
+        assert!(breakpoints.check_watchpoints(CHAR, Access::Read));
+        assert!(breakpoints.check_watchpoints(CHAR, Access::Write));
+        assert!(breakpoints.check_watchpoints(port, Access::Read));
+        assert!(!breakpoints.check_watchpoints(port, Access::Write));
+        assert!(!breakpoints.check_watchpoints(other, Access::Write));
+    }
+}
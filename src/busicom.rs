@@ -0,0 +1,385 @@
+// Busicom 141-PF printing calculator, the machine the 4004 was designed
+// for. Wires up the keyboard, printer and lamps around a user supplied ROM
+// dump the way the calculator does:
+//
+//   ROM 0 port  out  bit 0 keyboard shifter clock
+//                    bit 1 shifter data, both shifters
+//                    bit 2 printer shifter clock
+//   ROM 1 port  in   keyboard rows
+//   ROM 2 port  in   bit 0 printer drum index
+//                    bit 3 paper advance button
+//   RAM 0 out        bit 0 print in red
+//                    bit 1 fire print hammers
+//                    bit 3 advance paper
+//   RAM 1 out        bit 0 memory lamp
+//                    bit 1 overflow lamp
+//                    bit 2 minus lamp
+//   TEST             printer drum sector pulse
+//
+// The keyboard shifter is one 4003. The output set in it selects a column
+// of the key matrix and the keys down in that column read high on ROM 1.
+// The printer shifter is two chained 4003s selecting hammers, Qn firing
+// printer column n + 1.
+//
+// The drum has 13 characters round it and turns all the time. TEST pulses
+// as each row of characters comes under the hammers and the index bit is
+// high for row 0, so the program knows what firing a hammer will print.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use hardware::Hardware;
+use peripheral::{Group, Peripheral};
+use rom::{Direction, PinConfig, NUM_PINS};
+use shifter::ShiftRegister;
+use signal::TestSignal;
+
+// About 28ms a sector at the normal clock.
+pub const SECTOR_CYCLES: u64 = 2600;
+pub const SECTORS: u64 = 13;
+const PULSE_CYCLES: u64 = 500;
+
+// How long a key is held down and then left up before the next one. Long
+// enough for the program to scan and debounce it.
+pub const PRESS_CYCLES: u64 = 10000;
+pub const RELEASE_CYCLES: u64 = 10000;
+
+pub const DIGIT_COLUMNS: usize = 15;
+const PRINTER_COLUMNS: usize = 18;
+
+// Column, row of each key in the matrix.
+const KEYS: &[(&str, u8, u8)] = &[
+    ("cm", 0, 0), ("rm", 0, 1), ("m-", 0, 2), ("m+", 0, 3),
+    ("sqrt", 1, 0), ("%", 1, 1), ("m=-", 1, 2), ("m=+", 1, 3),
+    ("<>", 2, 0), ("/", 2, 1), ("*", 2, 2), ("=", 2, 3),
+    ("-", 3, 0), ("+", 3, 1), ("<>2", 3, 2), ("000", 3, 3),
+    ("9", 4, 0), ("6", 4, 1), ("3", 4, 2), (".", 4, 3),
+    ("8", 5, 0), ("5", 5, 1), ("2", 5, 2), ("00", 5, 3),
+    ("7", 6, 0), ("4", 6, 1), ("1", 6, 2), ("0", 6, 3),
+    ("sign", 7, 0), ("ex", 7, 1), ("ce", 7, 2), ("c", 7, 3),
+];
+
+// The two slide switches are read as columns 8 and 9.
+const DECIMAL_POINT_COLUMN: usize = 8;
+const ROUNDING_COLUMN: usize = 9;
+
+// What each drum row prints in the digit columns and the two symbol
+// columns.
+const DIGIT_DRUM: [&str; 13] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9", ".", ".", "-"];
+const SYMBOL_DRUM_1: [&str; 13] = ["<>", "+", "-", "x", "/", "M+", "M-", "^", "=", "sqrt", "%", "C", "R"];
+const SYMBOL_DRUM_2: [&str; 13] = ["#", "*", "I", "II", "III", "M+", "M-", "T", "K", "E", "Ex", "C", "M"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Float,
+    Round,
+    Truncate,
+}
+
+fn sector(cycle: u64) -> u64 {
+    (cycle / SECTOR_CYCLES) % SECTORS
+}
+
+// Sector pulses on TEST.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Drum;
+
+impl TestSignal for Drum {
+    fn level(&mut self, cycle: u64) -> bool {
+        cycle % SECTOR_CYCLES < PULSE_CYCLES
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Matrix(u8, u8), // column, row
+    PaperAdvance,
+}
+
+impl Key {
+    // Key names as in KEYS, or "feed" for the paper advance button.
+    pub fn from_name(name: &str) -> Option<Key> {
+        let name = name.to_lowercase();
+        if name == "feed" {
+            return Some(Key::PaperAdvance);
+        }
+        KEYS.iter().find(|k| k.0 == name).map(|k| Key::Matrix(k.1, k.2))
+    }
+}
+
+#[derive(Debug)]
+pub struct Keyboard {
+    shifter: Rc<RefCell<ShiftRegister>>,
+    queue: VecDeque<Key>,
+    down: Option<(Key, u64)>, // and when it went down
+    up_until: u64,
+    decimal_point: u8,
+    rounding: Rounding,
+}
+
+impl Keyboard {
+    fn new(shifter: Rc<RefCell<ShiftRegister>>) -> Keyboard {
+        Keyboard {
+            shifter,
+            queue: VecDeque::new(),
+            down: None,
+            up_until: 0,
+            decimal_point: 0,
+            rounding: Rounding::Float,
+        }
+    }
+
+    pub fn press(&mut self, key: Key) {
+        self.queue.push_back(key);
+    }
+
+    // Whitespace separated key names. Anything that isn't a key name is
+    // typed a character at a time, so "12+34=" works. The switches are set
+    // with dp0 to dp8 and float, round or truncate, straight away rather
+    // than in turn. Nothing happens if any of it isn't understood.
+    pub fn type_line(&mut self, line: &str) -> Result<(), String> {
+        let mut keys = Vec::new();
+        let mut decimal_point = self.decimal_point;
+        let mut rounding = self.rounding;
+        for word in line.split_whitespace() {
+            let places = word.strip_prefix("dp").and_then(|x| x.parse::<u8>().ok()).filter(|&x| x <= 8);
+            match (Key::from_name(word), places, word) {
+                (Some(key), _, _) => keys.push(key),
+                (_, Some(places), _) => decimal_point = places,
+                (_, _, "float") => rounding = Rounding::Float,
+                (_, _, "round") => rounding = Rounding::Round,
+                (_, _, "truncate") => rounding = Rounding::Truncate,
+                _ => for c in word.chars() {
+                    keys.push(Key::from_name(&c.to_string()).ok_or(format!("no key '{}'", c))?);
+                },
+            }
+        }
+        self.queue.extend(keys);
+        self.decimal_point = decimal_point;
+        self.rounding = rounding;
+        Ok(())
+    }
+
+    // Keys still to be pressed, including one that is down.
+    pub fn pending(&self) -> usize {
+        self.queue.len() + self.down.is_some() as usize
+    }
+
+    // 0-8 places.
+    pub fn set_decimal_point(&mut self, places: u8) {
+        assert!(places <= 8);
+        self.decimal_point = places;
+    }
+
+    pub fn set_rounding(&mut self, rounding: Rounding) {
+        self.rounding = rounding;
+    }
+
+    // Press and release keys in turn. Called whenever the ports are read,
+    // and by the host so keys still go by when the program isn't scanning.
+    pub fn update(&mut self, cycle: u64) {
+        if let Some((_, down_at)) = self.down {
+            if cycle >= down_at + PRESS_CYCLES {
+                self.down = None;
+                self.up_until = cycle + RELEASE_CYCLES;
+            }
+        }
+        if self.down.is_none() && cycle >= self.up_until {
+            self.down = self.queue.pop_front().map(|key| (key, cycle));
+        }
+    }
+
+    fn key_down(&self) -> Option<Key> {
+        self.down.map(|(key, _)| key)
+    }
+
+    fn rows(&self, column: usize) -> u8 {
+        match column {
+            DECIMAL_POINT_COLUMN => self.decimal_point,
+            ROUNDING_COLUMN => match self.rounding {
+                Rounding::Float => 0b0000,
+                Rounding::Round => 0b0001,
+                Rounding::Truncate => 0b1000,
+            },
+            _ => match self.key_down() {
+                Some(Key::Matrix(c, row)) if c as usize == column => 1 << row,
+                _ => 0,
+            },
+        }
+    }
+}
+
+// Keyboard rows on ROM 1.
+#[derive(Debug)]
+struct KeyboardPort(Rc<RefCell<Keyboard>>);
+
+impl Peripheral for KeyboardPort {
+    fn write(&mut self, _value: u8, _cycle: u64) {}
+
+    fn read(&mut self, cycle: u64) -> Option<u8> {
+        let mut keyboard = self.0.borrow_mut();
+        keyboard.update(cycle);
+        let columns = keyboard.shifter.borrow().outputs();
+        let rows = (0..10).filter(|&c| columns & (1 << c) != 0).fold(0, |rows, c| rows | keyboard.rows(c));
+        Some(rows)
+    }
+}
+
+// Drum index and the paper advance button on ROM 2.
+#[derive(Debug)]
+struct PrinterPort(Rc<RefCell<Keyboard>>);
+
+impl Peripheral for PrinterPort {
+    fn write(&mut self, _value: u8, _cycle: u64) {}
+
+    fn read(&mut self, cycle: u64) -> Option<u8> {
+        let mut keyboard = self.0.borrow_mut();
+        keyboard.update(cycle);
+        let index = (sector(cycle) == 0) as u8;
+        let advance = (keyboard.key_down() == Some(Key::PaperAdvance)) as u8;
+        Some(index | (advance << 3))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub text: String,
+    pub red: bool,
+}
+
+#[derive(Debug)]
+pub struct Printer {
+    shifter: Rc<RefCell<ShiftRegister>>,
+    columns: [Option<u64>; PRINTER_COLUMNS], // drum row struck in each column
+    red: bool,
+    control: u8,
+    lines: VecDeque<Line>,
+}
+
+impl Printer {
+    fn new(shifter: Rc<RefCell<ShiftRegister>>) -> Printer {
+        Printer {
+            shifter,
+            columns: [None; PRINTER_COLUMNS],
+            red: false,
+            control: 0,
+            lines: VecDeque::new(),
+        }
+    }
+
+    // Lines the paper has been advanced past, oldest first.
+    pub fn take_lines(&mut self) -> Vec<Line> {
+        self.lines.drain(..).collect()
+    }
+
+    // What has been struck on the line still under the hammers.
+    pub fn current_line(&self) -> String {
+        let text = |column: usize, drum: &[&str; 13]| match self.columns[column] {
+            Some(row) => drum[row as usize].to_string(),
+            None => " ".to_string(),
+        };
+        let mut line = String::new();
+        for column in 0..DIGIT_COLUMNS {
+            line.push_str(&text(column, &DIGIT_DRUM));
+        }
+        // column 16 has no hammer
+        line.push(' ');
+        line.push_str(&text(16, &SYMBOL_DRUM_1));
+        line.push(' ');
+        line.push_str(&text(17, &SYMBOL_DRUM_2));
+        line.trim_end().to_string()
+    }
+
+    fn strike(&mut self, cycle: u64) {
+        let hammers = self.shifter.borrow().outputs();
+        for (column, struck) in self.columns.iter_mut().enumerate() {
+            if hammers & (1 << column) != 0 {
+                *struck = Some(sector(cycle));
+            }
+        }
+    }
+
+    fn advance(&mut self) {
+        let text = self.current_line();
+        self.lines.push_back(Line { text, red: self.red });
+        self.columns = [None; PRINTER_COLUMNS];
+    }
+}
+
+// Printer control on RAM 0's output.
+#[derive(Debug)]
+struct PrinterControl(Rc<RefCell<Printer>>);
+
+impl Peripheral for PrinterControl {
+    fn write(&mut self, value: u8, cycle: u64) {
+        let mut printer = self.0.borrow_mut();
+        let rising = value & !printer.control;
+        printer.red = value & 0b0001 != 0;
+        if rising & 0b0010 != 0 {
+            printer.strike(cycle);
+        }
+        if rising & 0b1000 != 0 {
+            printer.advance();
+        }
+        printer.control = value;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Lamps {
+    pub memory: bool,
+    pub overflow: bool,
+    pub minus: bool,
+}
+
+// Handles on the calculator's peripherals once they are attached.
+#[derive(Debug)]
+pub struct Busicom {
+    pub keyboard: Rc<RefCell<Keyboard>>,
+    pub printer: Rc<RefCell<Printer>>,
+    lamps: Rc<Cell<u8>>,
+}
+
+impl Busicom {
+    // Wire everything up. `hardware` should have the ROM dump loaded.
+    pub fn attach(hardware: &mut Hardware) -> Busicom {
+        let keyboard_shifter = Rc::new(RefCell::new(ShiftRegister::new(0, 1)));
+        let printer_shifter = Rc::new(RefCell::new(ShiftRegister::new(2, 1).chained(2)));
+        let keyboard = Rc::new(RefCell::new(Keyboard::new(keyboard_shifter.clone())));
+        let printer = Rc::new(RefCell::new(Printer::new(printer_shifter.clone())));
+        let lamps = Rc::new(Cell::new(0));
+
+        let input = PinConfig { direction: Direction::Input, ..PinConfig::default() };
+        let mut rom2 = [PinConfig::default(); NUM_PINS];
+        rom2[0] = input;
+        rom2[3] = input;
+        hardware.configure_rom_port(0, 1, [input; NUM_PINS]);
+        hardware.configure_rom_port(0, 2, rom2);
+
+        let group = Group::new()
+            .with(Box::new(keyboard_shifter))
+            .with(Box::new(printer_shifter));
+        hardware.attach_rom_peripheral(0, 0, Box::new(group));
+        hardware.attach_rom_peripheral(0, 1, Box::new(KeyboardPort(keyboard.clone())));
+        hardware.attach_rom_peripheral(0, 2, Box::new(PrinterPort(keyboard.clone())));
+        hardware.attach_ram_peripheral(0, 0, Box::new(PrinterControl(printer.clone())));
+
+        let l = lamps.clone();
+        hardware.subscribe_ram_output(0, 1, Box::new(move |change| l.set(change.new)));
+
+        // two 4002s
+        hardware.fit_ram(0, 2);
+        hardware.attach_test(Box::new(Drum));
+
+        Busicom { keyboard, printer, lamps }
+    }
+
+    pub fn lamps(&self) -> Lamps {
+        let value = self.lamps.get();
+        Lamps {
+            memory: value & 0b0001 != 0,
+            overflow: value & 0b0010 != 0,
+            minus: value & 0b0100 != 0,
+        }
+    }
+}
@@ -0,0 +1,86 @@
+// 4004 timing
+// The CPU is driven by a two phase clock between 500 and 740 kHz. Each
+// machine cycle is eight clock periods (A1 A2 A3 M1 M2 X1 X2 X3) and most
+// instructions take one machine cycle. JCN, FIM, FIN, JUN, JMS and ISZ take
+// two. At 740 kHz that works out to the often quoted 10.8 us per cycle.
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_FREQUENCY: u32 = 740_000;
+pub const PHASES_PER_MACHINE_CYCLE: u64 = 8;
+
+// Don't bother sleeping for less than this. The OS won't honour it anyway.
+const MIN_SLEEP: Duration = Duration::from_millis(1);
+
+// If we fall this far behind (stopped in a debugger, machine under load)
+// give up on catching up and start pacing again from now.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clock {
+    frequency: u32 // Hz
+}
+
+impl Clock {
+    pub fn new(frequency: u32) -> Clock {
+        assert!(frequency > 0);
+        Clock { frequency }
+    }
+
+    pub fn frequency(&self) -> u32 {
+        self.frequency
+    }
+
+    // Clock periods in `cycles` machine cycles.
+    pub fn phases(&self, cycles: u64) -> u64 {
+        cycles * PHASES_PER_MACHINE_CYCLE
+    }
+
+    // Emulated time taken by `cycles` machine cycles.
+    pub fn duration(&self, cycles: u64) -> Duration {
+        let nanos = self.phases(cycles) as u128 * 1_000_000_000 / self.frequency as u128;
+        Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32)
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::new(DEFAULT_FREQUENCY)
+    }
+}
+
+// Keeps emulated time in step with wall clock time. Rather than sleeping a
+// fixed amount per instruction it works out where emulated time should be
+// relative to when pacing started, so sleep overshoot and time spent
+// emulating don't accumulate as drift.
+#[derive(Debug)]
+pub struct Pacer {
+    clock: Clock,
+    start: Instant,
+    start_cycles: u64
+}
+
+impl Pacer {
+    pub fn new(clock: Clock, cycles: u64) -> Pacer {
+        Pacer {
+            clock,
+            start: Instant::now(),
+            start_cycles: cycles
+        }
+    }
+
+    // Call with the current cycle count. Blocks until wall clock time has
+    // caught up with emulated time.
+    pub fn pace(&mut self, cycles: u64) {
+        let target = self.start + self.clock.duration(cycles - self.start_cycles);
+        let now = Instant::now();
+
+        if target > now + MIN_SLEEP {
+            sleep(target - now);
+        } else if now > target + MAX_LAG {
+            self.start = now;
+            self.start_cycles = cycles;
+        }
+    }
+}
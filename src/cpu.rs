@@ -1,8 +1,18 @@
 use std::fmt;
-use std::thread::sleep;
+use std::io::{Read, Write};
 use std::time::Duration;
 use std::collections::VecDeque;
-use hardware::Hardware;
+use std::mem;
+use breakpoint::{Access, Breakpoints};
+use clock::{Clock, Pacer};
+use disasm;
+use fault::{Fault, FaultPolicy, Policy};
+use hardware::{Hardware, Location};
+use history::{Delta, History};
+use instruction;
+use savestate;
+use savestate::SaveStateError;
+use trace::{Record, Tracer};
 
 // The 4004 is a 4 bit data / 12 bit address CPU therefore it doesn't really
 // fit into the standard integer types. Comments below show actual size of the
@@ -11,8 +21,25 @@ use hardware::Hardware;
 const NUM_INDEX_REGISTERS: usize = 16;
 const NUM_STACK_REGISTERS: usize = 3;
 
+// The 4040 adds a second bank of R0-R7 (24 index registers in all) and
+// deepens the stack to 7 levels.
+const NUM_BANKED_REGISTERS: usize = 8;
+const NUM_STACK_REGISTERS_4040: usize = 7;
+
+// Where the 4040 jumps to when it takes an interrupt.
+const INTERRUPT_VECTOR: u16 = 0x003;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    I4004,
+    I4040,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub struct CPU {               // actual register size
+    model: Model,
+
     accumulator: u8,           // u4
     carry: u8,                 // u1
 
@@ -22,6 +49,11 @@ pub struct CPU {               // actual register size
 
     index_registers: [u8; NUM_INDEX_REGISTERS], // u4
 
+    // 4040 only. R0-R7 of whichever bank isn't selected. SB0/SB1 swap them
+    // with the visible ones so everything else only sees 16 registers.
+    banked_registers: [u8; NUM_BANKED_REGISTERS], // u4
+    register_bank: u8,                            // u1
+
     // internal register used for ram bank switching
     command_control_register: u8,
 
@@ -29,58 +61,730 @@ pub struct CPU {               // actual register size
     ram_address_register_0: u8, // sent at X2
     ram_address_register_1: u8, // sent at X3
 
+    // 4040 only. CM-ROM line in use and the one DB0/DB1 asked for. The
+    // switch happens on the next JUN or JMS.
+    rom_bank: u8,               // u1
+    designated_rom_bank: u8,    // u1
+
+    // 4040 only. Interrupt handling.
+    interrupt_enable: bool,     // EIN/DIN
+    interrupt_active: bool,     // taken and not yet returned from with BBS
+    interrupt_saved_src: (u8, u8),
+    interrupt_saved_rom_bank: u8,
+    halted: bool,               // HLT
+
+    // machine cycles used by the instruction currently executing. Every
+    // machine cycle fetches one word from ROM so this counts fetches.
+    step_cycles: u32,
+
+    // machine cycles since reset
+    cycles: u64,
+
+    clock: Clock,
+
+    fault_policy: FaultPolicy,
+
+    // reads and writes made by the instruction currently executing
+    reads: Vec<Location>,
+    writes: Vec<MemoryWrite>,
+
+    breakpoints: Breakpoints,
+
+    history: Option<History>,
+
+    tracer: Option<Tracer>,
+
     hardware: Hardware
 }
 
+// A write made by an instruction, with what it replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub location: Location,
+    pub old: u8,
+    pub new: u8,
+}
+
+// Copy of everything in the CPU an instruction can change. Used by the
+// history to step backwards.
+#[derive(Debug, Clone)]
+pub struct Registers {
+    accumulator: u8,
+    carry: u8,
+    program_counter: u16,
+    program_counter_stack: VecDeque<u16>,
+    index_registers: [u8; NUM_INDEX_REGISTERS],
+    banked_registers: [u8; NUM_BANKED_REGISTERS],
+    register_bank: u8,
+    command_control_register: u8,
+    ram_address_register_0: u8,
+    ram_address_register_1: u8,
+    rom_bank: u8,
+    designated_rom_bank: u8,
+    interrupt_enable: bool,
+    interrupt_active: bool,
+    interrupt_saved_src: (u8, u8),
+    interrupt_saved_rom_bank: u8,
+    halted: bool,
+    cycles: u64,
+}
+
+impl Registers {
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+// What happened during a single call to step().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub address: u16,        // where the instruction was fetched from
+    pub opcode: u8,          // first word of the instruction
+    pub operand: Option<u8>, // second word of two word instructions
+    pub cycles: u32,         // machine cycles taken
+}
+
+impl Step {
+    // A JUN or JIN that lands on itself. Nothing but a reset gets the CPU out
+    // of one of these so there is no point carrying on.
+    pub fn is_halt_loop(&self, program_counter: u16) -> bool {
+        let opr = self.opcode >> 4;
+        let jin = opr == 0x3 && self.opcode & 0b0001 == 1;
+        (opr == 0x4 || jin) && program_counter == self.address
+    }
+}
+
+// Why one of the run_* functions returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(u16), // reached the requested address
+    CycleBudget,     // used up the requested number of machine cycles
+    Halt(u16),       // spinning in a jump to self
+    Condition,       // the run_while predicate returned false
+    Watchpoint(Location, Access),
+    Fault(Fault),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Breakpoint(pc) => write!(f, "breakpoint at {:03x}", pc),
+            StopReason::CycleBudget    => write!(f, "cycle budget used"),
+            StopReason::Halt(pc)       => write!(f, "halted at {:03x}", pc),
+            StopReason::Condition      => write!(f, "condition no longer holds"),
+            StopReason::Watchpoint(location, access) =>
+                write!(f, "watchpoint: {} {}", access, location),
+            StopReason::Fault(fault)   => write!(f, "fault: {}", fault),
+        }
+    }
+}
+
 impl CPU {
     pub fn new(hardware: Hardware) -> CPU {
+        CPU::with_model(hardware, Model::I4004)
+    }
+
+    pub fn with_model(hardware: Hardware, model: Model) -> CPU {
         CPU {
+            model,
             accumulator: 0,
             carry: 0,
             program_counter: 0,
-            program_counter_stack: VecDeque::with_capacity(NUM_STACK_REGISTERS),
+            program_counter_stack: VecDeque::with_capacity(NUM_STACK_REGISTERS_4040),
             index_registers: [0; NUM_INDEX_REGISTERS],
+            banked_registers: [0; NUM_BANKED_REGISTERS],
+            register_bank: 0,
             command_control_register: 0,
             ram_address_register_0: 0,
             ram_address_register_1: 0,
-            hardware: hardware
+            rom_bank: 0,
+            designated_rom_bank: 0,
+            interrupt_enable: false,
+            interrupt_active: false,
+            interrupt_saved_src: (0, 0),
+            interrupt_saved_rom_bank: 0,
+            halted: false,
+            step_cycles: 0,
+            cycles: 0,
+            clock: Clock::default(),
+            fault_policy: FaultPolicy::default(),
+            reads: Vec::new(),
+            writes: Vec::new(),
+            breakpoints: Breakpoints::new(),
+            history: None,
+            tracer: None,
+            hardware
         }
     }
 
-    pub fn _reset(&mut self) {
+    pub fn reset(&mut self) {
         self.accumulator = 0;
         self.carry = 0;
         self.program_counter = 0;
         self.program_counter_stack.clear();
-        for x in 0..NUM_INDEX_REGISTERS {
-            self.index_registers[x] = 0;
-        }
+        self.index_registers = [0; NUM_INDEX_REGISTERS];
+        self.banked_registers = [0; NUM_BANKED_REGISTERS];
+        self.register_bank = 0;
         self.command_control_register = 0;
+        self.rom_bank = 0;
+        self.designated_rom_bank = 0;
+        self.interrupt_enable = false;
+        self.interrupt_active = false;
+        self.halted = false;
+        self.cycles = 0;
+        if let Some(ref mut history) = self.history {
+            history.clear();
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn accumulator(&self) -> u8 {
+        self.accumulator
+    }
+
+    pub fn carry(&self) -> u8 {
+        self.carry
+    }
+
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter
+    }
+
+    pub fn program_counter_stack(&self) -> &VecDeque<u16> {
+        &self.program_counter_stack
+    }
+
+    // Index register as the program currently sees it, i.e. R0-R7 come from
+    // the selected bank on the 4040.
+    pub fn index_register(&self, register: usize) -> u8 {
+        self.index_registers[register]
+    }
+
+    pub fn register_bank(&self) -> u8 {
+        self.register_bank
+    }
+
+    pub fn rom_bank(&self) -> u8 {
+        self.rom_bank
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn command_control_register(&self) -> u8 {
+        self.command_control_register
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    pub fn fault_policy(&self) -> FaultPolicy {
+        self.fault_policy
+    }
+
+    pub fn set_fault_policy(&mut self, fault_policy: FaultPolicy) {
+        self.fault_policy = fault_policy;
+    }
+
+    // Emulated time since reset.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.duration(self.cycles)
+    }
+
+    pub fn hardware(&self) -> &Hardware {
+        &self.hardware
+    }
+
+    pub fn hardware_mut(&mut self) -> &mut Hardware {
+        &mut self.hardware
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    // Start recording the last `depth` instructions so they can be undone.
+    pub fn enable_history(&mut self, depth: usize) {
+        match self.history {
+            Some(ref mut history) => history.set_depth(depth),
+            None => self.history = Some(History::new(depth)),
+        }
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    // RAM and ROM port reads made by the last instruction stepped.
+    pub fn last_reads(&self) -> &[Location] {
+        &self.reads
+    }
+
+    // Writes made by the last instruction stepped.
+    pub fn last_writes(&self) -> &[MemoryWrite] {
+        &self.writes
+    }
+
+    // Undo the last instruction. False if there is no history left.
+    pub fn step_back(&mut self) -> bool {
+        let delta = match self.history.as_mut().and_then(|h| h.pop()) {
+            Some(delta) => delta,
+            None => return false,
+        };
+
+        self.hardware.set_cycle(delta.registers.cycles);
+        for write in delta.writes.iter().rev() {
+            // Fails only if the RAM was refitted since and the chip is gone,
+            // in which case there is nothing left to put the old value in.
+            let _ = self.hardware.restore(write.location, write.old);
+        }
+        self.restore_registers(delta.registers);
+        self.reads.clear();
+        self.writes.clear();
+        true
     }
 
-    pub fn run(&mut self) {
+    // Undo up to `n` instructions. Returns how many were undone.
+    pub fn step_back_n(&mut self, n: usize) -> usize {
+        (0..n).take_while(|_| self.step_back()).count()
+    }
+
+    // Step back until just before the most recent instruction that wrote to
+    // `location`. If nothing in the history did, steps back as far as it can
+    // and returns false.
+    pub fn reverse_to_write(&mut self, location: Location) -> bool {
         loop {
-            println!("{}", self);
-            self.run_instruction();
-            sleep(Duration::from_millis(10));
+            let found = match self.history.as_ref().and_then(|h| h.iter().next()) {
+                Some(delta) => delta.writes.iter().any(|w| w.location == location),
+                None => return false,
+            };
+            self.step_back();
+            if found {
+                return true;
+            }
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            accumulator: self.accumulator,
+            carry: self.carry,
+            program_counter: self.program_counter,
+            program_counter_stack: self.program_counter_stack.clone(),
+            index_registers: self.index_registers,
+            banked_registers: self.banked_registers,
+            register_bank: self.register_bank,
+            command_control_register: self.command_control_register,
+            ram_address_register_0: self.ram_address_register_0,
+            ram_address_register_1: self.ram_address_register_1,
+            rom_bank: self.rom_bank,
+            designated_rom_bank: self.designated_rom_bank,
+            interrupt_enable: self.interrupt_enable,
+            interrupt_active: self.interrupt_active,
+            interrupt_saved_src: self.interrupt_saved_src,
+            interrupt_saved_rom_bank: self.interrupt_saved_rom_bank,
+            halted: self.halted,
+            cycles: self.cycles,
+        }
+    }
+
+    fn restore_registers(&mut self, r: Registers) {
+        self.accumulator = r.accumulator;
+        self.carry = r.carry;
+        self.program_counter = r.program_counter;
+        self.program_counter_stack = r.program_counter_stack;
+        self.index_registers = r.index_registers;
+        self.banked_registers = r.banked_registers;
+        self.register_bank = r.register_bank;
+        self.command_control_register = r.command_control_register;
+        self.ram_address_register_0 = r.ram_address_register_0;
+        self.ram_address_register_1 = r.ram_address_register_1;
+        self.rom_bank = r.rom_bank;
+        self.designated_rom_bank = r.designated_rom_bank;
+        self.interrupt_enable = r.interrupt_enable;
+        self.interrupt_active = r.interrupt_active;
+        self.interrupt_saved_src = r.interrupt_saved_src;
+        self.interrupt_saved_rom_bank = r.interrupt_saved_rom_bank;
+        self.halted = r.halted;
+        self.cycles = r.cycles;
+    }
+
+    // Write the whole machine to `w`. See savestate.rs for the framing.
+    pub fn save_state(&self, w: &mut dyn Write) -> Result<(), SaveStateError> {
+        savestate::write_header(w)?;
+
+        savestate::write_u8(w, self.model as u8)?;
+        savestate::write_u8(w, self.accumulator)?;
+        savestate::write_u8(w, self.carry)?;
+        savestate::write_u16(w, self.program_counter)?;
+        savestate::write_u8(w, self.program_counter_stack.len() as u8)?;
+        for &address in &self.program_counter_stack {
+            savestate::write_u16(w, address)?;
+        }
+        savestate::write_bytes(w, &self.index_registers)?;
+        savestate::write_bytes(w, &self.banked_registers)?;
+        savestate::write_u8(w, self.register_bank)?;
+        savestate::write_u8(w, self.command_control_register)?;
+        savestate::write_u8(w, self.ram_address_register_0)?;
+        savestate::write_u8(w, self.ram_address_register_1)?;
+        savestate::write_u8(w, self.rom_bank)?;
+        savestate::write_u8(w, self.designated_rom_bank)?;
+        savestate::write_bool(w, self.interrupt_enable)?;
+        savestate::write_bool(w, self.interrupt_active)?;
+        savestate::write_u8(w, self.interrupt_saved_src.0)?;
+        savestate::write_u8(w, self.interrupt_saved_src.1)?;
+        savestate::write_u8(w, self.interrupt_saved_rom_bank)?;
+        savestate::write_bool(w, self.halted)?;
+        savestate::write_u64(w, self.cycles)?;
+
+        self.hardware.save_state(w)
+    }
+
+    // Restore a state written by save_state(). The CPU is left untouched if
+    // anything is wrong with the file.
+    pub fn load_state(&mut self, r: &mut dyn Read) -> Result<(), SaveStateError> {
+        savestate::read_header(r)?;
+
+        let model = match savestate::read_u8(r)? {
+            0 => Model::I4004,
+            1 => Model::I4040,
+            _ => return Err(SaveStateError::Corrupt("model")),
+        };
+        let accumulator = savestate::read_bounded(r, 16, "accumulator")?;
+        let carry = savestate::read_bounded(r, 2, "carry")?;
+        let program_counter = read_address(r, "program counter")?;
+        let depth = savestate::read_u8(r)? as usize;
+        if depth > NUM_STACK_REGISTERS_4040 {
+            return Err(SaveStateError::Corrupt("stack depth"));
+        }
+        let mut program_counter_stack = VecDeque::with_capacity(NUM_STACK_REGISTERS_4040);
+        for _ in 0..depth {
+            program_counter_stack.push_back(read_address(r, "stack")?);
+        }
+        let mut index_registers = [0; NUM_INDEX_REGISTERS];
+        savestate::read_nibbles(r, &mut index_registers, "index register")?;
+        let mut banked_registers = [0; NUM_BANKED_REGISTERS];
+        savestate::read_nibbles(r, &mut banked_registers, "index register")?;
+        let register_bank = savestate::read_bounded(r, 2, "register bank")?;
+        let command_control_register = savestate::read_bounded(r, 16, "command register")?;
+        let ram_address_register_0 = savestate::read_bounded(r, 16, "SRC register")?;
+        let ram_address_register_1 = savestate::read_bounded(r, 16, "SRC register")?;
+        let rom_bank = savestate::read_bounded(r, 2, "ROM bank")?;
+        let designated_rom_bank = savestate::read_bounded(r, 2, "ROM bank")?;
+        let interrupt_enable = savestate::read_bool(r, "interrupt enable")?;
+        let interrupt_active = savestate::read_bool(r, "interrupt active")?;
+        let saved_0 = savestate::read_bounded(r, 16, "SRC register")?;
+        let saved_1 = savestate::read_bounded(r, 16, "SRC register")?;
+        let interrupt_saved_rom_bank = savestate::read_bounded(r, 2, "ROM bank")?;
+        let halted = savestate::read_bool(r, "halt")?;
+        let cycles = savestate::read_u64(r)?;
+
+        self.hardware.load_state(r)?;
+
+        self.model = model;
+        self.accumulator = accumulator;
+        self.carry = carry;
+        self.program_counter = program_counter;
+        self.program_counter_stack = program_counter_stack;
+        self.index_registers = index_registers;
+        self.banked_registers = banked_registers;
+        self.register_bank = register_bank;
+        self.command_control_register = command_control_register;
+        self.ram_address_register_0 = ram_address_register_0;
+        self.ram_address_register_1 = ram_address_register_1;
+        self.rom_bank = rom_bank;
+        self.designated_rom_bank = designated_rom_bank;
+        self.interrupt_enable = interrupt_enable;
+        self.interrupt_active = interrupt_active;
+        self.interrupt_saved_src = (saved_0, saved_1);
+        self.interrupt_saved_rom_bank = interrupt_saved_rom_bank;
+        self.halted = halted;
+        self.cycles = cycles;
+        if let Some(ref mut history) = self.history {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    // Run forever in real time at the configured clock rate. Only returns if
+    // the program halts or faults.
+    pub fn run(&mut self) -> StopReason {
+        self.run_watching(|_| true)
+    }
+
+    // Same as run() but calls `watch` after every instruction, to keep a
+    // display up to date say. Stops when it returns false.
+    pub fn run_watching<F>(&mut self, mut watch: F) -> StopReason
+        where F: FnMut(&CPU) -> bool
+    {
+        let mut pacer = Pacer::new(self.clock, self.cycles);
+        self.run_with(|cpu, _| {
+            if !watch(cpu) {
+                return Some(StopReason::Condition);
+            }
+            pacer.pace(cpu.cycles);
+            None
+        })
+    }
+
+    // Execute exactly one instruction. When a fault traps the program counter
+    // is left pointing at the offending instruction.
+    pub fn step(&mut self) -> Result<Step, Fault> {
+        let registers = self.history.as_ref().map(|_| self.registers());
+        let before = (self.accumulator, self.carry, self.index_registers, self.cycles);
+        self.reads.clear();
+        self.writes.clear();
+
+        let result = self.execute();
+
+        if let Ok(ref step) = result {
+            if self.tracer.is_some() {
+                self.trace(step, before);
+            }
+        }
+
+        if let (Ok(_), Some(registers)) = (&result, registers) {
+            let delta = Delta { registers, writes: self.writes.clone() };
+            if let Some(ref mut history) = self.history {
+                history.push(delta);
+            }
         }
+
+        result
     }
 
-    fn run_instruction(&mut self) {
+    fn execute(&mut self) -> Result<Step, Fault> {
+        self.step_cycles = 0;
+
+        if self.model == Model::I4040 {
+            self.check_interrupt();
+
+            if self.halted {
+                // sits doing nothing until interrupted
+                self.step_cycles = 1;
+                self.cycles += 1;
+                let address = self.program_counter;
+                return Ok(Step { address, opcode: 0x01, operand: None, cycles: 1 });
+            }
+        }
+
+        let address = self.program_counter;
+        let opcode = self.hardware.rom_read_word(self.rom_bank, address);
+        let operand = if instruction::is_two_word(opcode) {
+            Some(self.hardware.rom_read_word(self.rom_bank, (address + 1) & 0xfff))
+        } else {
+            None
+        };
+
+        match self.run_instruction() {
+            Ok(()) => {
+                self.cycles += self.step_cycles as u64;
+                Ok(Step { address, opcode, operand, cycles: self.step_cycles })
+            },
+            Err(fault) => {
+                self.program_counter = address;
+                Err(fault)
+            }
+        }
+    }
+
+    // Run until at least `cycles` machine cycles have elapsed.
+    pub fn run_for(&mut self, cycles: u64) -> StopReason {
+        if cycles == 0 {
+            return StopReason::CycleBudget;
+        }
+        let mut elapsed = 0;
+        self.run_with(|_, step| {
+            elapsed += step.cycles as u64;
+            if elapsed >= cycles { Some(StopReason::CycleBudget) } else { None }
+        })
+    }
+
+    // Run until the program counter reaches `address`. Always executes at
+    // least one instruction so it can be called repeatedly on a loop.
+    pub fn run_until(&mut self, address: u16) -> StopReason {
+        self.run_with(|cpu, _| {
+            if cpu.program_counter == address {
+                Some(StopReason::Breakpoint(address))
+            } else {
+                None
+            }
+        })
+    }
+
+    // Run for as long as `predicate` holds. It is checked before every
+    // instruction including the first.
+    pub fn run_while<F>(&mut self, mut predicate: F) -> StopReason
+        where F: FnMut(&CPU) -> bool
+    {
+        if !predicate(self) {
+            return StopReason::Condition;
+        }
+        self.run_with(|cpu, _| {
+            if predicate(cpu) { None } else { Some(StopReason::Condition) }
+        })
+    }
+
+    fn trace(&mut self, step: &Step, before: (u8, u8, [u8; NUM_INDEX_REGISTERS], u64)) {
+        let class = instruction::class(self.model, step.opcode);
+        if !self.tracer.as_ref().is_some_and(|t| t.accepts(step.address, class)) {
+            return;
+        }
+        let (accumulator, carry, index_registers, cycle) = before;
+
+        let mut bytes = vec![step.opcode];
+        bytes.extend(step.operand);
+
+        let mnemonic = disasm::decode(self.model, &bytes, step.address).to_string();
+        let registers = (0..NUM_INDEX_REGISTERS)
+            .filter(|&r| index_registers[r] != self.index_registers[r])
+            .map(|r| (r as u8, index_registers[r], self.index_registers[r]))
+            .collect();
+
+        let record = Record {
+            cycle,
+            address: step.address,
+            bytes,
+            mnemonic,
+            class,
+            accumulator: (accumulator, self.accumulator),
+            carry: (carry, self.carry),
+            registers,
+            writes: self.writes.clone(),
+        };
+
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.record(&record);
+        }
+    }
+
+    // Breakpoint at the current pc that wants to stop.
+    fn breakpoint_hit(&mut self) -> bool {
+        let mut breakpoints = mem::take(&mut self.breakpoints);
+        let hit = breakpoints.check_breakpoints(self);
+        self.breakpoints = breakpoints;
+        hit
+    }
+
+    // Watchpoint covering anything the last instruction touched.
+    fn watchpoint_hit(&self) -> Option<StopReason> {
+        let reads = self.reads.iter().map(|&l| (l, Access::Read));
+        let writes = self.writes.iter().map(|w| (w.location, Access::Write));
+
+        reads.chain(writes)
+            .find(|&(location, access)| self.breakpoints.check_watchpoints(location, access))
+            .map(|(location, access)| StopReason::Watchpoint(location, access))
+    }
+
+    // Common loop behind the run_* functions. Breakpoints are checked before
+    // every instruction but the first, so a run can resume from one, and
+    // watchpoints after every instruction. `stop` is consulted after every
+    // instruction that didn't fault, halt or hit a watchpoint.
+    fn run_with<F>(&mut self, mut stop: F) -> StopReason
+        where F: FnMut(&CPU, &Step) -> Option<StopReason>
+    {
+        let mut first = true;
+
+        loop {
+            if !first && !self.breakpoints.is_empty() && self.breakpoint_hit() {
+                return StopReason::Breakpoint(self.program_counter);
+            }
+            first = false;
+
+            let step = match self.step() {
+                Ok(step) => step,
+                Err(fault) => return StopReason::Fault(fault),
+            };
+
+            // a halted 4040 with interrupts off can never wake up, and a
+            // jump to self is how a 4040 with them on waits for one
+            let waiting = self.model == Model::I4040 && self.interrupt_enable;
+            if (self.halted || step.is_halt_loop(self.program_counter)) && !waiting {
+                return StopReason::Halt(step.address);
+            }
+
+            if !self.breakpoints.is_empty() {
+                if let Some(reason) = self.watchpoint_hit() {
+                    return reason;
+                }
+            }
+
+            if let Some(reason) = stop(self, &step) {
+                return reason;
+            }
+        }
+    }
+
+    fn run_instruction(&mut self) -> Result<(), Fault> {
+        let address = self.program_counter;
         let (opr, opa) = self.rom_read_word();
+        let invalid = Fault::InvalidOpcode { address, opcode: (opr << 4) | opa };
 
         match opr {
-            0x0 => { }, // NOP What if opa != 0? Still a NOP?
+            0x0 => match self.model {
+                Model::I4004 => { }, // NOP What if opa != 0? Still a NOP?
+                Model::I4040 => match opa {
+                    0x0 => { }, // NOP
+                    0x1 => self.opa_hlt(),
+                    0x2 => self.opa_bbs()?,
+                    0x3 => self.opa_lcr(),
+                    0x4 => self.opa_or(4), // OR4
+                    0x5 => self.opa_or(5), // OR5
+                    0x6 => self.opa_an(6), // AN6
+                    0x7 => self.opa_an(7), // AN7
+                    0x8 => self.opa_db(0), // DB0
+                    0x9 => self.opa_db(1), // DB1
+                    0xa => self.opa_sb(0), // SB0
+                    0xb => self.opa_sb(1), // SB1
+                    0xc => self.opa_ein(),
+                    0xd => self.opa_din(),
+                    0xe => self.opa_rpm()?,
+                    _   => self.fault(invalid)?,
+                },
+            },
             0x1 => self.opr_jcn(opa),
-            0x2 => match opa & 0b0001 {
-                0 => self.opr_fim(opa),
-                1 => self.opr_src(opa),
-                _ => panic!(), // remove compile error
+            0x2 => if opa & 0b0001 == 0 {
+                self.opr_fim(opa)
+            } else {
+                self.opr_src(opa)
             },
-            0x3 => match opa & 0b0001 {
-                0 => self.opr_fin(opa),
-                1 => self.opr_jin(opa),
-                _ => panic!(), // remove compile error
+            0x3 => if opa & 0b0001 == 0 {
+                self.opr_fin(opa)
+            } else {
+                self.opr_jin(opa)
             },
             0x4 => self.opr_jun(opa),
             0x5 => self.opr_jms(opa),
@@ -90,26 +794,25 @@ impl CPU {
             0x9 => self.opr_sub(opa),
             0xa => self.opr_ld(opa),
             0xb => self.opr_xch(opa),
-            0xc => self.opr_bbl(opa),
+            0xc => self.opr_bbl(opa)?,
             0xd => self.opr_ldm(opa),
             0xe => match opa {
-                0x0 => self.opa_wrm(),
-                0x1 => self.opa_wmp(),
-                0x2 => self.opa_wrr(),
-                0x3 => {}, // WPM - NOP for now. 4008/4009
-                0x4 => self.opa_wrn(0), // WR0
-                0x5 => self.opa_wrn(1), // WR1
-                0x6 => self.opa_wrn(2), // WR2
-                0x7 => self.opa_wrn(3), // WR3
-                0x8 => self.opa_sbm(),
-                0x9 => self.opa_rdm(),
+                0x0 => self.opa_wrm()?,
+                0x1 => self.opa_wmp()?,
+                0x2 => self.opa_wrr()?,
+                0x3 => self.opa_wpm()?,
+                0x4 => self.opa_wrn(0)?, // WR0
+                0x5 => self.opa_wrn(1)?, // WR1
+                0x6 => self.opa_wrn(2)?, // WR2
+                0x7 => self.opa_wrn(3)?, // WR3
+                0x8 => self.opa_sbm()?,
+                0x9 => self.opa_rdm()?,
                 0xa => self.opa_rdr(),
-                0xb => self.opa_adm(),
-                0xc => self.opa_rdn(0), // RD0
-                0xd => self.opa_rdn(1), // RD1
-                0xe => self.opa_rdn(2), // RD2
-                0xf => self.opa_rdn(3), // RD3
-                _   => panic!("Unrecognized instruction: {:0x}{:0x}", opr, opa),
+                0xb => self.opa_adm()?,
+                0xc => self.opa_rdn(0)?, // RD0
+                0xd => self.opa_rdn(1)?, // RD1
+                0xe => self.opa_rdn(2)?, // RD2
+                _   => self.opa_rdn(3)?, // RD3
             },
             0xf => { // Accumulator Group Instructions
                 match opa {
@@ -127,75 +830,143 @@ impl CPU {
                     0xb => self.opa_daa(),
                     0xc => self.opa_kbp(),
                     0xd => self.opa_dcl(),
-                    _   => panic!("Unrecognized instruction: {:0x}{:0x}", opr, opa),
+                    _   => self.fault(invalid)?, // NOP on the real thing
                 }
             },
-            _   => panic!("Unrecognized instruction: {:0x}{:0x}", opr, opa),
+            _   => self.fault(invalid)?,
+        }
+
+        Ok(())
+    }
+
+    // Apply the fault policy. Ok means carry on doing what the hardware
+    // would do.
+    fn fault(&self, fault: Fault) -> Result<(), Fault> {
+        match self.fault_policy.policy(&fault) {
+            Policy::Trap    => Err(fault),
+            Policy::Warn    => {
+                eprintln!("warning: {}", fault);
+                Ok(())
+            },
+            Policy::Emulate => Ok(()),
         }
     }
 
-    fn ram_read_char(&self) -> u8 {
+    // Unmapped RAM reads float to 0 and writes are lost.
+    fn recover<T>(&self, result: Result<T, Fault>, floating: T) -> Result<T, Fault> {
+        match result {
+            Ok(x) => Ok(x),
+            Err(fault) => self.fault(fault).map(|_| floating),
+        }
+    }
+
+    // DCL drives CM-RAM0 alone for 0, otherwise a combination of
+    // CM-RAM1-3 which the board decodes into banks 1-7.
+    fn ram_bank(&self) -> u8 {
+        self.command_control_register
+    }
+
+    fn ram_read_char(&mut self) -> Result<u8, Fault> {
+        let bank = self.ram_bank();
         let chip = self.ram_address_register_0 >> 2;
         let register = self.ram_address_register_0 & 0b0011;
         let character = self.ram_address_register_1;
 
-        self.hardware.ram_read_char(chip, register, character)
+        self.reads.push(Location::RamChar { bank, chip, register, character });
+        self.recover(self.hardware.ram_read_char(bank, chip, register, character), 0)
     }
 
-    fn ram_write_char(&mut self, value: u8) {
+    // All writes go through here so they can be recorded.
+    fn write(&mut self, location: Location, value: u8) -> Result<(), Fault> {
+        let old = match self.hardware.read(location) {
+            Ok(x) => x,
+            Err(fault) => return self.fault(fault),
+        };
+        // readable so writable
+        self.hardware.set_cycle(self.cycles + self.step_cycles as u64);
+        self.hardware.write(location, value)?;
+        self.writes.push(MemoryWrite { location, old, new: value });
+        Ok(())
+    }
+
+    fn ram_write_char(&mut self, value: u8) -> Result<(), Fault> {
+        let bank = self.ram_bank();
         let chip = self.ram_address_register_0 >> 2;
         let register = self.ram_address_register_0 & 0b0011;
         let character = self.ram_address_register_1;
 
-        self.hardware.ram_write_char(chip, register, character, value)
+        self.write(Location::RamChar { bank, chip, register, character }, value)
     }
 
-    fn ram_read_status(&self, status: u8) -> u8 {
+    fn ram_read_status(&mut self, status: u8) -> Result<u8, Fault> {
+        let bank = self.ram_bank();
         let chip = self.ram_address_register_0 >> 2;
         let register = self.ram_address_register_0 & 0b0011;
-        self.hardware.ram_read_status(chip, register, status)
+        self.reads.push(Location::RamStatus { bank, chip, register, status });
+        self.recover(self.hardware.ram_read_status(bank, chip, register, status), 0)
     }
 
-    fn ram_write_status(&mut self, status: u8, value: u8) {
+    fn ram_write_status(&mut self, status: u8, value: u8) -> Result<(), Fault> {
+        let bank = self.ram_bank();
         let chip = self.ram_address_register_0 >> 2;
         let register = self.ram_address_register_0 & 0b0011;
-        self.hardware.ram_write_status(chip, register, status, value)
+        self.write(Location::RamStatus { bank, chip, register, status }, value)
     }
 
-    fn ram_write_output(&mut self, value: u8) {
+    fn ram_write_output(&mut self, value: u8) -> Result<(), Fault> {
+        let bank = self.ram_bank();
         let chip = self.ram_address_register_0 >> 2;
-        self.hardware.ram_write_output(chip, value);
+        self.write(Location::RamOutput { bank, chip }, value)
     }
 
     fn rom_read_word(&mut self) -> (u8, u8) {
-        let word = self.hardware.rom_read_word(self.program_counter);
-        self.program_counter += 1;
+        let word = self.hardware.rom_read_word(self.rom_bank, self.program_counter);
+        self.program_counter = (self.program_counter + 1) & 0xfff;
+        self.step_cycles += 1;
 
         ((word >> 4) & 0b1111, word & 0b1111)
     }
 
-    fn rom_read_port(&self) -> u8 {
-        let chip = self.ram_address_register_0 >> 2;
-        self.hardware.rom_read_port(chip)
+    // ROM ports use all four bits of the chip number sent with SRC.
+    fn rom_read_port(&mut self) -> u8 {
+        let chip = self.ram_address_register_0;
+        self.reads.push(Location::RomPort { bank: self.rom_bank, chip });
+        if let Some(nibble) = self.hardware.program_ram_read_back(self.rom_bank, chip) {
+            return nibble;
+        }
+        self.hardware.set_cycle(self.cycles + self.step_cycles as u64);
+        self.hardware.rom_read_port(self.rom_bank, chip)
     }
 
-    fn rom_write_port(&mut self, value: u8) {
-        let chip = self.ram_address_register_0 >> 2;
-        self.hardware.rom_write_port(chip, value);
+    fn rom_write_port(&mut self, value: u8) -> Result<(), Fault> {
+        let chip = self.ram_address_register_0;
+        let bank = self.rom_bank;
+        self.write(Location::RomPort { bank, chip }, value)
+    }
+
+    fn stack_depth(&self) -> usize {
+        match self.model {
+            Model::I4004 => NUM_STACK_REGISTERS,
+            Model::I4040 => NUM_STACK_REGISTERS_4040,
+        }
     }
 
     fn program_counter_stack_push(&mut self) {
-        if self.program_counter_stack.len() == NUM_STACK_REGISTERS {
+        if self.program_counter_stack.len() == self.stack_depth() {
             self.program_counter_stack.pop_back();
         }
         self.program_counter_stack.push_front(self.program_counter);
     }
 
-    fn program_counter_stack_pop(&mut self) {
+    fn program_counter_stack_pop(&mut self) -> Result<(), Fault> {
         self.program_counter = match self.program_counter_stack.pop_front() {
             Some(x) => x,
-            None    => panic!("Program counter stack underflow.")
+            None    => {
+                self.fault(Fault::StackUnderflow { address: self.program_counter.wrapping_sub(1) & 0xfff })?;
+                0
+            }
         };
+        Ok(())
     }
 
     // =========================V operands in order V=========================
@@ -206,7 +977,10 @@ impl CPU {
         let invert_cond = opa & 0b1000 == 0b1000;
         let accumulator_cond = (self.accumulator == 0) && (opa & 0b0100 == 0b0100);
         let carry_cond  = (self.carry == 1) && (opa & 0b0010 == 0b0010);
-        let test_signal_cond = false && (opa & 0b0001 == 0b0001); // TODO implement
+        // TEST is sampled as the second word is fetched. The condition is met
+        // when the pin is low.
+        let test = self.hardware.test(self.cycles + self.step_cycles as u64);
+        let test_signal_cond = !test && (opa & 0b0001 == 0b0001);
 
         let cond = accumulator_cond || carry_cond || test_signal_cond;
 
@@ -222,11 +996,19 @@ impl CPU {
     }
 
     fn opr_fin(&mut self, opa: u8) {
-        let (d2, d1) = self.rom_read_word();
+        // The second machine cycle fetches the data from the current page at
+        // the address held in register pair 0. As with JIN the pc has already
+        // moved on so the last word of a page fetches from the next page.
+        let ph = self.program_counter >> 8;
+        let address = (ph << 8)
+                    + ((self.index_registers[0] as u16) << 4)
+                    + self.index_registers[1] as u16;
+        let word = self.hardware.rom_read_word(self.rom_bank, address);
+        self.step_cycles += 1;
+
         // could make a write_register_pair() function.
-        self.index_registers[opa as usize] = d2;
-        self.index_registers[(opa + 1) as usize] = d1;
-        self.program_counter += 1;
+        self.index_registers[opa as usize] = word >> 4;
+        self.index_registers[(opa + 1) as usize] = word & 0b1111;
     }
 
     fn opr_jin(&mut self, opa: u8) {
@@ -240,6 +1022,7 @@ impl CPU {
 
     fn opr_jun(&mut self, opa: u8) {
         let (a2, a1) = self.rom_read_word();
+        self.rom_bank = self.designated_rom_bank;
         self.program_counter = ((opa as u16) << 8)
                              + ((a2 as u16) << 4)
                              + a1 as u16;
@@ -247,6 +1030,7 @@ impl CPU {
 
     fn opr_jms(&mut self, opa: u8) {
         let (a2, a1) = self.rom_read_word();
+        self.rom_bank = self.designated_rom_bank;
         self.program_counter_stack_push();
         self.program_counter = ((opa as u16) << 8)
                              + ((a2 as u16) << 4)
@@ -291,14 +1075,13 @@ impl CPU {
     }
 
     fn opr_xch(&mut self, opa: u8) {
-        let tmp: u8 = self.accumulator;
-        self.accumulator = self.index_registers[opa as usize];
-        self.index_registers[opa as usize] = tmp;
+        std::mem::swap(&mut self.accumulator, &mut self.index_registers[opa as usize]);
     }
 
-    fn opr_bbl(&mut self, opa: u8) {
-        self.program_counter_stack_pop();
+    fn opr_bbl(&mut self, opa: u8) -> Result<(), Fault> {
+        self.program_counter_stack_pop()?;
         self.accumulator = opa;
+        Ok(())
     }
 
     fn opr_ldm(&mut self, opa: u8) {
@@ -307,48 +1090,83 @@ impl CPU {
 
     // =================V  input/output & RAM instructions V=================
 
-    fn opa_rdm(&mut self) {
-        self.accumulator = self.ram_read_char();
+    fn opa_rdm(&mut self) -> Result<(), Fault> {
+        self.accumulator = self.ram_read_char()?;
+        Ok(())
     }
 
-    fn opa_wrm(&mut self) {
+    fn opa_wrm(&mut self) -> Result<(), Fault> {
         let acc = self.accumulator;
-        self.ram_write_char(acc);
+        self.ram_write_char(acc)
     }
 
-    fn opa_rdn(&mut self, n: u8) { // RD0, RD1, etc
-        self.accumulator = self.ram_read_status(n);
+    fn opa_rdn(&mut self, n: u8) -> Result<(), Fault> { // RD0, RD1, etc
+        self.accumulator = self.ram_read_status(n)?;
+        Ok(())
     }
 
     fn opa_rdr(&mut self) {
         self.accumulator = self.rom_read_port();
     }
 
-    fn opa_wrn(&mut self, n: u8) { // WR0, WR1, etc
+    fn opa_wrn(&mut self, n: u8) -> Result<(), Fault> { // WR0, WR1, etc
         let acc = self.accumulator;
-        self.ram_write_status(n, acc);
+        self.ram_write_status(n, acc)
     }
 
-    fn opa_wrr(&mut self) {
+    fn opa_wrr(&mut self) -> Result<(), Fault> {
         let acc = self.accumulator;
-        self.rom_write_port(acc);
+        self.rom_write_port(acc)
     }
 
-    fn opa_wmp(&mut self) {
+    // Program RAM word addressed by SRC and the 4008's address port, and
+    // the nibble the flip-flop picks. None if no program RAM is fitted
+    // there, WPM and RPM then do nothing.
+    fn program_ram_location(&self) -> Option<Location> {
+        let low = (self.ram_address_register_0 << 4) | self.ram_address_register_1;
+        let address = self.hardware.program_ram_address(low)?;
+        let half = self.hardware.program_ram()?.half();
+        Some(Location::ProgramRam { address, half })
+    }
+
+    fn step_program_ram_half(&mut self) -> Result<(), Fault> {
+        let half = self.hardware.read(Location::ProgramRamHalf)?;
+        self.write(Location::ProgramRamHalf, half ^ 1)
+    }
+
+    fn opa_wpm(&mut self) -> Result<(), Fault> {
+        let location = match self.program_ram_location() {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+        if self.hardware.program_ram_write_enabled() {
+            let acc = self.accumulator;
+            self.write(location, acc)?;
+        } else {
+            self.reads.push(location);
+            let nibble = self.hardware.read(location)?;
+            self.write(Location::ProgramRamReadBack, nibble)?;
+        }
+        self.step_program_ram_half()
+    }
+
+    fn opa_wmp(&mut self) -> Result<(), Fault> {
         let acc = self.accumulator;
-        self.ram_write_output(acc);
+        self.ram_write_output(acc)
     }
 
-    fn opa_adm(&mut self) {
-        self.accumulator = self.ram_read_char() + self.accumulator + self.carry;
+    fn opa_adm(&mut self) -> Result<(), Fault> {
+        self.accumulator = self.ram_read_char()? + self.accumulator + self.carry;
         self.carry = self.accumulator >> 4;
         self.accumulator &= 0b1111;
+        Ok(())
     }
 
-    fn opa_sbm(&mut self) {
-        self.accumulator = (self.ram_read_char() ^ 0b1111) + self.accumulator + (self.carry ^ 1);
+    fn opa_sbm(&mut self) -> Result<(), Fault> {
+        self.accumulator = (self.ram_read_char()? ^ 0b1111) + self.accumulator + (self.carry ^ 1);
         self.carry = self.accumulator >> 4;
         self.accumulator &= 0b1111;
+        Ok(())
     }
 
 
@@ -385,7 +1203,7 @@ impl CPU {
 
     fn opa_rar(&mut self) {
         let carry = self.accumulator & 0b0001;
-        self.accumulator = self.accumulator >> 1 + self.carry << 3;
+        self.accumulator = (self.accumulator >> 1) + (self.carry << 3);
         self.carry = carry;
     }
 
@@ -434,26 +1252,604 @@ impl CPU {
     fn opa_dcl(&mut self) {
         self.command_control_register = self.accumulator & 0b111;
     }
+    // =================V 4040 instructions in order V=================
+
+    // The 4040 checks its INT line before each instruction. Taking an
+    // interrupt is an implied JMS to 003 in ROM bank 0. Further interrupts
+    // are held off until the handler returns with BBS.
+    fn check_interrupt(&mut self) {
+        if !self.interrupt_enable || self.interrupt_active || !self.hardware.interrupt(self.cycles) {
+            return;
+        }
+
+        self.halted = false;
+        self.interrupt_active = true;
+        self.interrupt_saved_src = (self.ram_address_register_0, self.ram_address_register_1);
+        self.interrupt_saved_rom_bank = self.rom_bank;
+        self.program_counter_stack_push();
+        self.program_counter = INTERRUPT_VECTOR;
+        self.rom_bank = 0;
+        self.designated_rom_bank = 0;
+    }
+
+    fn opa_hlt(&mut self) {
+        self.halted = true;
+    }
+
+    fn opa_bbs(&mut self) -> Result<(), Fault> {
+        self.program_counter_stack_pop()?;
+        if self.interrupt_active {
+            let (x2, x3) = self.interrupt_saved_src;
+            self.ram_address_register_0 = x2;
+            self.ram_address_register_1 = x3;
+            self.rom_bank = self.interrupt_saved_rom_bank;
+            self.designated_rom_bank = self.interrupt_saved_rom_bank;
+            self.interrupt_active = false;
+        }
+        Ok(())
+    }
+
+    fn opa_lcr(&mut self) {
+        self.accumulator = self.command_control_register;
+    }
+
+    fn opa_or(&mut self, register: usize) { // OR4, OR5
+        self.accumulator |= self.index_registers[register];
+    }
+
+    fn opa_an(&mut self, register: usize) { // AN6, AN7
+        self.accumulator &= self.index_registers[register];
+    }
+
+    fn opa_db(&mut self, bank: u8) { // DB0, DB1
+        self.designated_rom_bank = bank;
+    }
+
+    fn opa_sb(&mut self, bank: u8) { // SB0, SB1
+        if bank != self.register_bank {
+            self.index_registers[..NUM_BANKED_REGISTERS].swap_with_slice(&mut self.banked_registers);
+            self.register_bank = bank;
+        }
+    }
+
+    fn opa_ein(&mut self) {
+        self.interrupt_enable = true;
+    }
+
+    fn opa_din(&mut self) {
+        self.interrupt_enable = false;
+    }
+
+    // Reads program RAM a nibble at a time, see opa_wpm.
+    fn opa_rpm(&mut self) -> Result<(), Fault> {
+        match self.program_ram_location() {
+            Some(location) => {
+                self.reads.push(location);
+                self.accumulator = self.hardware.read(location)?;
+                self.step_program_ram_half()
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+fn read_address(r: &mut dyn Read, field: &'static str) -> Result<u16, SaveStateError> {
+    let address = savestate::read_u16(r)?;
+    if address > 0xfff {
+        return Err(SaveStateError::Corrupt(field));
+    }
+    Ok(address)
 }
 
 impl fmt::Display for CPU {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        try!(write!(f, "acc: {:x} carry: {} pc: {:03x} pc stack: {:?}\n",
+        writeln!(f, "acc: {:x} carry: {} pc: {:03x} pc stack: {:?}",
                self.accumulator, self.carry, self.program_counter,
                self.program_counter_stack
-               ));
+               )?;
 
         // tidy this up later
-        try!(write!(f, "r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}\n",
+        writeln!(f, "r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}",
                0, self.index_registers[0], 1, self.index_registers[1],
                2, self.index_registers[2], 3, self.index_registers[3],
                4, self.index_registers[4], 5, self.index_registers[5],
-               6, self.index_registers[6], 7, self.index_registers[7]));
+               6, self.index_registers[6], 7, self.index_registers[7])?;
 
-        write!(f, "r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}\n",
+        writeln!(f, "r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}   r{:02}: {:x} r{:02}: {:x}",
                8, self.index_registers[8], 9, self.index_registers[9],
                10, self.index_registers[10], 11, self.index_registers[11],
                12, self.index_registers[12], 13, self.index_registers[13],
-               14, self.index_registers[14], 15, self.index_registers[15])
+               14, self.index_registers[14], 15, self.index_registers[15])?;
+
+        if self.model == Model::I4040 {
+            let bank = self.register_bank ^ 1;
+            let r = &self.banked_registers;
+            writeln!(f, "bank {}: r00: {:x} r01: {:x}   r02: {:x} r03: {:x}   r04: {:x} r05: {:x}   r06: {:x} r07: {:x}",
+                   bank, r[0], r[1], r[2], r[3], r[4], r[5], r[6], r[7])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use hardware::Hardware;
+    use shifter::ShiftRegister;
+    use signal::Waveform;
+    use program_ram::ProgramRam;
+    use super::*;
+
+    fn cpu(rom: &[u8]) -> CPU {
+        CPU::new(Hardware::new(rom.to_vec()).unwrap())
+    }
+
+    // roms/example_01: FIM P0 $A2, LD R0, ADD R1, XCH R1, then JUN to itself
+    const EXAMPLE: [u8; 7] = [0x20, 0xa2, 0xa0, 0x81, 0xb1, 0x40, 0x05];
+
+    // FIM P0 $00, SRC P0, LDM 5, WRM, LDM 7, WRM, LDM 9, XCH R0
+    const WRITES: [u8; 9] = [0x20, 0x00, 0x21, 0xd5, 0xe0, 0xd7, 0xe0, 0xd9, 0xb0];
+    const CHAR: Location = Location::RamChar { bank: 0, chip: 0, register: 0, character: 0 };
+
+    fn saved(cpu: &CPU) -> Vec<u8> {
+        let mut state = Vec::new();
+        cpu.save_state(&mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn step() {
+        let mut cpu = cpu(&EXAMPLE);
+        let step = cpu.step().unwrap();
+        assert_eq!(step, Step { address: 0, opcode: 0x20, operand: Some(0xa2), cycles: 2 });
+        assert_eq!((cpu.index_register(0), cpu.index_register(1)), (0xa, 0x2));
+        assert_eq!(cpu.program_counter(), 2);
+        assert_eq!(cpu.cycles(), 2);
+    }
+
+    #[test]
+    fn run_for_stops_when_the_budget_is_used() {
+        let mut cpu = cpu(&EXAMPLE);
+        assert_eq!(cpu.run_for(3), StopReason::CycleBudget);
+        assert_eq!(cpu.program_counter(), 3);
+        assert_eq!(cpu.run_for(100), StopReason::Halt(5));
+        assert_eq!(cpu.index_register(1), 0xc);
+    }
+
+    #[test]
+    fn run_until_stops_at_the_address() {
+        let mut cpu = cpu(&EXAMPLE);
+        assert_eq!(cpu.run_until(4), StopReason::Breakpoint(4));
+        assert_eq!(cpu.accumulator(), 0xc);
+    }
+
+    #[test]
+    fn run_while_stops_when_the_predicate_fails() {
+        let mut cpu = cpu(&EXAMPLE);
+        assert_eq!(cpu.run_while(|cpu| cpu.program_counter() < 3), StopReason::Condition);
+        assert_eq!(cpu.program_counter(), 3);
+        // checked before the first instruction too
+        assert_eq!(cpu.run_while(|_| false), StopReason::Condition);
+        assert_eq!(cpu.program_counter(), 3);
+    }
+
+    #[test]
+    fn runs_stop_on_faults() {
+        // BBL with nothing to return to
+        let mut cpu = cpu(&[0xc0]);
+        assert_eq!(cpu.run_for(10), StopReason::Fault(Fault::StackUnderflow { address: 0 }));
+        assert_eq!(cpu.program_counter(), 0);
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut cpu = cpu(&WRITES);
+        cpu.run_for(6);
+        let state = saved(&cpu);
+
+        let mut other = self::cpu(&WRITES);
+        other.load_state(&mut &state[..]).unwrap();
+        assert_eq!(saved(&other), state);
+        assert_eq!(other.program_counter(), 6);
+        assert_eq!(other.hardware().read(CHAR), Ok(5));
+    }
+
+    #[test]
+    fn load_state_rejects_bad_input() {
+        let mut cpu = cpu(&EXAMPLE);
+        cpu.run_for(3);
+        let state = saved(&cpu);
+        let load = |cpu: &mut CPU, state: &[u8]| cpu.load_state(&mut &state[..]).unwrap_err();
+
+        let mut bad = state.clone();
+        bad[0] = b'X';
+        match load(&mut cpu, &bad) {
+            SaveStateError::BadMagic => {},
+            e => panic!("{}", e),
+        }
+
+        let mut bad = state.clone();
+        bad[4] = bad[4].wrapping_add(1);
+        match load(&mut cpu, &bad) {
+            SaveStateError::UnsupportedVersion(_) => {},
+            e => panic!("{}", e),
+        }
+
+        let mut bad = state.clone();
+        bad[7] = 0x10; // accumulator
+        match load(&mut cpu, &bad) {
+            SaveStateError::Corrupt("accumulator") => {},
+            e => panic!("{}", e),
+        }
+
+        match load(&mut cpu, &state[..state.len() - 1]) {
+            SaveStateError::Io(_) => {},
+            e => panic!("{}", e),
+        }
+
+        assert_eq!(saved(&cpu), state);
+    }
+
+    #[test]
+    fn step_back() {
+        let mut cpu = cpu(&EXAMPLE);
+        cpu.enable_history(8);
+        assert!(!cpu.step_back());
+
+        let mut states = vec![saved(&cpu)];
+        for _ in 0..4 {
+            cpu.step().unwrap();
+            states.push(saved(&cpu));
+        }
+        states.pop();
+        while let Some(state) = states.pop() {
+            assert!(cpu.step_back());
+            assert_eq!(saved(&cpu), state);
+        }
+        assert!(!cpu.step_back());
+    }
+
+    #[test]
+    fn step_back_is_limited_to_the_history_depth() {
+        let mut cpu = cpu(&EXAMPLE);
+        cpu.enable_history(2);
+        cpu.run_for(100);
+        assert_eq!(cpu.step_back_n(10), 2);
+    }
+
+    #[test]
+    fn reverse_to_write() {
+        let mut cpu = cpu(&WRITES);
+        cpu.enable_history(16);
+        cpu.run_for(9);
+        assert_eq!(cpu.program_counter(), 9);
+
+        assert!(cpu.reverse_to_write(CHAR));
+        assert_eq!(cpu.program_counter(), 6);
+        assert_eq!(cpu.hardware().read(CHAR), Ok(5));
+
+        assert!(cpu.reverse_to_write(CHAR));
+        assert_eq!(cpu.program_counter(), 4);
+        assert_eq!(cpu.hardware().read(CHAR), Ok(0));
+
+        assert!(!cpu.reverse_to_write(CHAR));
+        assert_eq!(cpu.program_counter(), 0);
+    }
+
+    #[test]
+    fn runs_across_rom_chips() {
+        // JUN 1FE, 1FE: LDM 4, LDM 5, 200: XCH R2. Chip 0 is blank at 0FE-100
+        let mut rom = vec![0; 0x201];
+        rom[..2].copy_from_slice(&[0x41, 0xfe]);
+        rom[0x1fe..].copy_from_slice(&[0xd4, 0xd5, 0xb2]);
+        let mut cpu = cpu(&rom);
+        cpu.run_for(5);
+        assert_eq!(cpu.program_counter(), 0x201);
+        assert_eq!((cpu.accumulator(), cpu.index_register(2)), (0, 5));
+    }
+
+    #[test]
+    fn rom_port_chip_select() {
+        // FIM P0 $A0, SRC P0, LDM 7, WRR, FIM P0 $20, SRC P0, LDM 3, WRR
+        let mut cpu = cpu(&[0x20, 0xa0, 0x21, 0xd7, 0xe2, 0x20, 0x20, 0x21, 0xd3, 0xe2]);
+        cpu.run_for(10);
+        // all four bits pick the ROM, where only the top two pick a RAM
+        let port = |chip| cpu.hardware().read(Location::RomPort { bank: 0, chip }).unwrap();
+        assert_eq!((port(0xa), port(2), port(0)), (7, 3, 0));
+        assert_eq!(cpu.last_writes()[0].location, Location::RomPort { bank: 0, chip: 2 });
+    }
+
+    #[test]
+    fn dcl_selects_the_ram_bank() {
+        // LDM 3, DCL, FIM P0 $40, SRC P0, LDM 9, WRM, LDM 0, DCL, LDM 2, WRM,
+        // LDM $B, DCL, LDM 4, WRM
+        let mut cpu = cpu(&[0xd3, 0xfd, 0x20, 0x40, 0x21, 0xd9, 0xe0, 0xd0, 0xfd, 0xd2, 0xe0,
+                            0xdb, 0xfd, 0xd4, 0xe0]);
+        cpu.run_for(11);
+        let read = |cpu: &CPU, bank| cpu.hardware().ram_read_char(bank, 1, 0, 0).unwrap();
+        assert_eq!((read(&cpu, 3), read(&cpu, 0)), (9, 2));
+        cpu.run_for(4); // only three bits of DCL count
+        assert_eq!(cpu.command_control_register(), 3);
+        assert_eq!(read(&cpu, 3), 4);
+    }
+
+    #[test]
+    fn unmapped_ram_chip() {
+        // FIM P0 $40, SRC P0, LDM 5, WRM, RDM
+        let rom = [0x20, 0x40, 0x21, 0xd5, 0xe0, 0xe9];
+        let fault = Fault::UnmappedRamChip { bank: 0, chip: 1 };
+
+        for &policy in &[Policy::Trap, Policy::Warn, Policy::Emulate] {
+            let mut cpu = cpu(&rom);
+            cpu.hardware_mut().fit_ram(0, 1);
+            cpu.set_fault_policy(FaultPolicy::all(policy));
+            if policy == Policy::Trap {
+                assert_eq!(cpu.run_for(10), StopReason::Fault(fault));
+                continue;
+            }
+            assert_eq!(cpu.run_for(5), StopReason::CycleBudget);
+            assert!(cpu.last_writes().is_empty()); // the write went nowhere
+            cpu.step().unwrap();
+            assert_eq!(cpu.accumulator(), 0); // and the read floats
+        }
+    }
+
+    // FIM P1 $E0, SRC P1, LDM 1, WRR       high address 1
+    // FIM P1 $F0, SRC P1, LDM 1, WRR       write enable
+    // FIM P0 $23, SRC P0, LDM $A, WPM, LDM 5, WPM
+    // FIM P1 $F0, SRC P1, LDM 0, WRR       write disable
+    const WPM: [u8; 22] = [0x22, 0xe0, 0x23, 0xd1, 0xe2, 0x22, 0xf0, 0x23, 0xd1, 0xe2,
+                           0x20, 0x23, 0x21, 0xda, 0xe3, 0xd5, 0xe3,
+                           0x22, 0xf0, 0x23, 0xd0, 0xe2];
+
+    fn program_ram_cpu(model: Model, rest: &[u8]) -> CPU {
+        let mut hardware = Hardware::new([&WPM[..], rest].concat()).unwrap();
+        hardware.fit_program_ram(ProgramRam::new(1, 1));
+        CPU::with_model(hardware, model)
+    }
+
+    #[test]
+    fn wpm_writes_high_nibble_first() {
+        let mut cpu = program_ram_cpu(Model::I4004, &[]);
+        let half = |cpu: &CPU| cpu.hardware().program_ram().unwrap().half();
+        cpu.run_until(15);
+        assert_eq!(cpu.last_writes()[0].location, Location::ProgramRam { address: 0x123, half: 0 });
+        assert_eq!((cpu.hardware().rom_read_word(0, 0x123), half(&cpu)), (0xa0, 1));
+        cpu.run_until(17);
+        assert_eq!((cpu.hardware().rom_read_word(0, 0x123), half(&cpu)), (0xa5, 0));
+        assert!(cpu.hardware().program_ram_write_enabled());
+        cpu.run_until(22);
+        assert!(!cpu.hardware().program_ram_write_enabled());
+    }
+
+    #[test]
+    fn wpm_read_back() {
+        // FIM P1 $E0, SRC P0, WPM, SRC P1, RDR, XCH R4, SRC P0, WPM, SRC P1, RDR
+        let mut cpu = program_ram_cpu(Model::I4004, &[0x22, 0xe0, 0x21, 0xe3, 0x23, 0xea, 0xb4,
+                                                      0x21, 0xe3, 0x23, 0xea]);
+        cpu.run_until(33);
+        // with writing disabled WPM copies into the latch RDR reads
+        assert_eq!((cpu.index_register(4), cpu.accumulator()), (0xa, 0x5));
+        assert_eq!(cpu.hardware().rom_read_word(0, 0x123), 0xa5);
+    }
+
+    #[test]
+    fn rpm_4040() {
+        // SRC P0, RPM, XCH R4, RPM
+        let mut cpu = program_ram_cpu(Model::I4040, &[0x21, 0x0e, 0xb4, 0x0e]);
+        cpu.run_until(26);
+        assert_eq!((cpu.index_register(4), cpu.accumulator()), (0xa, 0x5));
+    }
+
+    #[test]
+    fn jcn_test_pin() {
+        // JCN TZ 010 and JCN TN 010: TZ jumps when the pin is low
+        for &(opcode, level, taken) in &[(0x11, false, true), (0x11, true, false),
+                                         (0x19, false, false), (0x19, true, true)] {
+            let mut cpu = cpu(&[opcode, 0x10]);
+            cpu.hardware_mut().set_test(level);
+            cpu.step().unwrap();
+            assert_eq!(cpu.program_counter(), if taken { 0x10 } else { 2 });
+        }
+    }
+
+    #[test]
+    fn jcn_test_waveform() {
+        // wait for TEST to go high, then for it to go low again:
+        // JCN TZ 000, JCN TN 002, LDM 1
+        let mut waiting = cpu(&[0x11, 0x00, 0x19, 0x02, 0xd1]);
+        waiting.hardware_mut().attach_test(Box::new("0:0 20:1 40:0".parse::<Waveform>().unwrap()));
+        assert_eq!(waiting.run_until(2), StopReason::Breakpoint(2));
+        assert!(waiting.cycles() >= 20);
+        assert_eq!(waiting.run_until(4), StopReason::Breakpoint(4));
+        assert!(waiting.cycles() >= 40);
+
+        // set_test takes over from the waveform
+        let mut pinned = cpu(&[0x11, 0x00, 0xd1]);
+        pinned.hardware_mut().attach_test(Box::new(Waveform::new(false)));
+        pinned.hardware_mut().set_test(true);
+        pinned.step().unwrap();
+        assert_eq!(pinned.program_counter(), 2);
+    }
+
+    fn cpu_4040(rom: &[u8]) -> CPU {
+        CPU::with_model(Hardware::new(rom.to_vec()).unwrap(), Model::I4040)
+    }
+
+    #[test]
+    fn accumulator_and_register_logic_4040() {
+        // FIM P2 $35, FIM P3 $6C, LDM 8, OR4, OR5, AN6, AN7, LDM 5, DCL, LDM 0, LCR
+        let mut cpu = cpu_4040(&[0x24, 0x35, 0x26, 0x6c, 0xd8, 0x04, 0x05, 0x06, 0x07,
+                                 0xd5, 0xfd, 0xd0, 0x03]);
+        cpu.run_for(5);
+        let mut accumulator = Vec::new();
+        for _ in 0..4 {
+            cpu.step().unwrap();
+            accumulator.push(cpu.accumulator());
+        }
+        assert_eq!(accumulator, [0xb, 0xf, 0x6, 0x4]);
+        cpu.run_for(4);
+        assert_eq!(cpu.accumulator(), 5);
+    }
+
+    #[test]
+    fn register_banks_4040() {
+        // LDM 7, XCH R0, LDM 3, XCH R8, SB1, LDM 9, XCH R0, SB0
+        let mut cpu = cpu_4040(&[0xd7, 0xb0, 0xd3, 0xb8, 0x0b, 0xd9, 0xb0, 0x0a]);
+        cpu.run_for(5);
+        assert_eq!(cpu.register_bank(), 1);
+        assert_eq!(cpu.index_register(0), 0);
+        assert_eq!(cpu.index_register(8), 3); // R8-R15 aren't banked
+        cpu.run_for(2);
+        assert_eq!(cpu.index_register(0), 9);
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_bank(), 0);
+        assert_eq!(cpu.index_register(0), 7);
+    }
+
+    #[test]
+    fn rom_bank_switching_4040() {
+        // bank 0: DB1, JUN 010 ... 020: LDM 7
+        // bank 1: 010: LDM 6, DB0, JUN 020
+        let mut bank0 = vec![0; 0x21];
+        bank0[..3].copy_from_slice(&[0x09, 0x40, 0x10]);
+        bank0[0x20] = 0xd7;
+        let mut bank1 = vec![0; 0x14];
+        bank1[0x10..].copy_from_slice(&[0xd6, 0x08, 0x40, 0x20]);
+        let mut cpu = cpu_4040(&bank0);
+        cpu.hardware_mut().load_rom_bank(1, bank1).unwrap();
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.rom_bank(), 0); // only changes on the next jump
+        cpu.step().unwrap();
+        assert_eq!((cpu.rom_bank(), cpu.program_counter()), (1, 0x10));
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator(), 6);
+        cpu.run_for(3);
+        assert_eq!((cpu.rom_bank(), cpu.program_counter()), (0, 0x20));
+        cpu.step().unwrap();
+        assert_eq!(cpu.accumulator(), 7);
+    }
+
+    #[test]
+    fn jms_switches_rom_bank_4040() {
+        // bank 0: DB1, JMS 010. bank 1: 010: BBL 5
+        let mut bank1 = vec![0; 0x11];
+        bank1[0x10] = 0xc5;
+        let mut cpu = cpu_4040(&[0x09, 0x50, 0x10]);
+        cpu.hardware_mut().load_rom_bank(1, bank1).unwrap();
+        cpu.run_for(4);
+        assert_eq!((cpu.rom_bank(), cpu.program_counter(), cpu.accumulator()), (1, 3, 5));
+    }
+
+    #[test]
+    fn stack_depth() {
+        // eight JMSes each calling the next
+        let rom: Vec<u8> = (0..8).flat_map(|n| vec![0x50, 2 * n + 2]).collect();
+        let returns = |cpu: &CPU| cpu.program_counter_stack().iter().cloned().collect::<Vec<_>>();
+
+        let mut cpu = cpu(&rom);
+        cpu.run_for(16);
+        assert_eq!(returns(&cpu), [16, 14, 12]);
+
+        let mut cpu = cpu_4040(&rom);
+        cpu.run_for(16);
+        assert_eq!(returns(&cpu), [16, 14, 12, 10, 8, 6, 4]);
+    }
+
+    #[test]
+    fn halt_4040() {
+        let mut cpu = cpu_4040(&[0x01]);
+        assert_eq!(cpu.run_for(10), StopReason::Halt(0));
+        assert!(cpu.is_halted());
+
+        // JUN 005, 003: LDM 9, BBS, 005: EIN, HLT, JUN 007
+        let mut cpu = cpu_4040(&[0x40, 0x05, 0x00, 0xd9, 0x02, 0x0c, 0x01, 0x40, 0x07]);
+        assert_eq!(cpu.run_for(20), StopReason::CycleBudget);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.program_counter(), 7);
+
+        cpu.hardware_mut().set_interrupt(true);
+        cpu.step().unwrap();
+        assert!(!cpu.is_halted());
+        assert_eq!((cpu.program_counter(), cpu.accumulator()), (4, 9));
+        cpu.hardware_mut().set_interrupt(false);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter(), 7);
+    }
+
+    #[test]
+    fn jump_to_self_waits_for_an_interrupt_4040() {
+        // EIN, JUN 001, and the handler at 003: LDM 9
+        let mut cpu = cpu_4040(&[0x0c, 0x40, 0x01, 0xd9]);
+        assert_eq!(cpu.run_for(100), StopReason::CycleBudget);
+
+        let request = Waveform::new(false).at(cpu.cycles() + 10, true);
+        cpu.hardware_mut().attach_interrupt(Box::new(request));
+        assert_eq!(cpu.run_until(4), StopReason::Breakpoint(4));
+        assert_eq!(cpu.accumulator(), 9);
+        assert_eq!(cpu.program_counter_stack().front(), Some(&1));
+
+        // but with interrupts off it never ends
+        let mut cpu = cpu_4040(&[0x0c, 0x0d, 0x40, 0x02]);
+        assert_eq!(cpu.run_for(100), StopReason::Halt(2));
+    }
+
+    #[test]
+    fn interrupt_entry_and_return_4040() {
+        // 000: JUN 010    003: JUN 020
+        // 010: FIM P0 $12, SRC P0, EIN, LDM 5, WRM, JUN 015
+        // 020: FIM P1 $45, SRC P1, BBS
+        let mut rom = vec![0; 0x24];
+        rom[..5].copy_from_slice(&[0x40, 0x10, 0x00, 0x40, 0x20]);
+        rom[0x10..0x18].copy_from_slice(&[0x20, 0x12, 0x21, 0x0c, 0xd5, 0xe0, 0x40, 0x15]);
+        rom[0x20..0x24].copy_from_slice(&[0x22, 0x45, 0x23, 0x02]);
+        let mut cpu = cpu_4040(&rom);
+        cpu.hardware_mut().fit_ram(0, 4);
+        cpu.run_for(30);
+
+        // interrupts are held off while one is being handled
+        cpu.hardware_mut().set_interrupt(true);
+        cpu.run_until(0x23);
+        assert_eq!(cpu.program_counter_stack().len(), 1);
+        cpu.hardware_mut().set_interrupt(false);
+        cpu.step().unwrap();
+        assert!(cpu.program_counter_stack().is_empty());
+
+        // the SRC address is put back for the interrupted program
+        cpu.run_until(0x16);
+        let location = Location::RamChar { bank: 0, chip: 0, register: 1, character: 2 };
+        assert_eq!(cpu.last_writes()[0].location, location);
+    }
+
+    #[test]
+    fn step_back_after_the_ram_is_removed() {
+        let mut cpu = cpu(&WRITES);
+        cpu.enable_history(8);
+        cpu.run_for(5); // to just after the first WRM
+        cpu.hardware_mut().fit_ram(0, 0);
+        assert!(cpu.step_back());
+        assert_eq!(cpu.program_counter(), 4);
+        assert_eq!(cpu.accumulator(), 5);
+    }
+
+    #[test]
+    fn step_back_leaves_peripherals_alone() {
+        // FIM P0 $00, SRC P0, LDM 3, WRR: clock and data high on rom0
+        let mut cpu = cpu(&[0x20, 0x00, 0x21, 0xd3, 0xe2]);
+        let shifter = Rc::new(RefCell::new(ShiftRegister::new(0, 1)));
+        cpu.hardware_mut().attach_rom_peripheral(0, 0, Box::new(shifter.clone()));
+        cpu.enable_history(8);
+
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(shifter.borrow().outputs(), 0b1);
+
+        assert!(cpu.step_back());
+        assert_eq!(cpu.hardware().rom_port_outputs(0, 0), 0);
+        cpu.step().unwrap();
+        assert_eq!(shifter.borrow().outputs(), 0b1);
     }
 }
@@ -0,0 +1,231 @@
+// Disassembler
+// Turns ROM words back into instructions, written the way the szyc
+// assembler listings write them: mnemonic, then operands separated by
+// spaces, registers as R0-R15, register pairs as P0-P7, data as $ hex.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use cpu::Model;
+use instruction;
+use instruction::Operands;
+
+// JCN condition codes that have a name. Anything else is shown as a number.
+pub const CONDITIONS: [(&str, u8); 6] = [
+    ("TZ", 0b0001), // test = 0
+    ("C1", 0b0010), // carry = 1
+    ("AZ", 0b0100), // accumulator = 0
+    ("TN", 0b1001), // test = 1
+    ("C0", 0b1010), // carry = 0
+    ("AN", 0b1100), // accumulator != 0
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register(u8),  // R0-R15
+    Pair(u8),      // P0-P7
+    Data(u8),      // 4 bit immediate
+    Byte(u8),      // 8 bit immediate
+    Address(u16),  // jump target, always a full 12 bit address
+    Condition(u8), // JCN condition code
+}
+
+impl Operand {
+    fn write(&self, f: &mut dyn fmt::Write, labels: Option<&BTreeSet<u16>>) -> fmt::Result {
+        match *self {
+            Operand::Register(r) => write!(f, "R{}", r),
+            Operand::Pair(p) => write!(f, "P{}", p),
+            Operand::Data(d) | Operand::Byte(d) => write!(f, "${:02X}", d),
+            Operand::Address(a) => match labels {
+                Some(labels) if labels.contains(&a) => write!(f, "{}", label(a)),
+                _ => write!(f, "${:03X}", a),
+            },
+            Operand::Condition(c) => match CONDITIONS.iter().find(|&&(_, x)| x == c) {
+                Some(&(name, _)) => write!(f, "{}", name),
+                None => write!(f, "${:X}", c),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+}
+
+impl Instruction {
+    // Where this instruction can jump to, if anywhere fixed.
+    pub fn target(&self) -> Option<u16> {
+        self.operands.iter().filter_map(|o| match *o {
+            Operand::Address(a) => Some(a),
+            _ => None,
+        }).next()
+    }
+
+    // Like Display but jump targets in `labels` are written as labels.
+    pub fn text(&self, labels: Option<&BTreeSet<u16>>) -> String {
+        let mut s = format!("{:<3}", self.mnemonic);
+        for operand in &self.operands {
+            s.push(' ');
+            // writing to a String can't fail
+            operand.write(&mut s, labels).unwrap();
+        }
+        s.trim_end().to_string()
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text(None))
+    }
+}
+
+pub fn label(address: u16) -> String {
+    format!("L{:03X}", address)
+}
+
+// Decode the instruction at the start of `words`, which was read from
+// `address`. Opcodes the model doesn't have, and two word instructions cut
+// short by the end of `words`, come back as a DB of the first word.
+pub fn decode(model: Model, words: &[u8], address: u16) -> Instruction {
+    let opcode = words[0];
+    let opa = opcode & 0b1111;
+
+    let data_byte = |operands: Vec<Operand>| -> Option<(Vec<u8>, Vec<Operand>)> {
+        words.get(1).map(|&b| (vec![opcode, b], operands))
+    };
+    // JCN and ISZ stay in the page the pc is on after the second word
+    let in_page = |b: u8| ((address + 2) & 0xf00) | b as u16;
+
+    let mnemonic = match instruction::mnemonic(model, opcode) {
+        Some(m) if !instruction::is_two_word(opcode) || words.len() > 1 => m,
+        _ => return Instruction {
+            address,
+            bytes: vec![opcode],
+            mnemonic: "DB",
+            operands: vec![Operand::Byte(opcode)],
+        },
+    };
+
+    let (bytes, operands) = match instruction::operands(opcode) {
+        Operands::ConditionAddress =>
+            data_byte(vec![Operand::Condition(opa), Operand::Address(in_page(words[1]))]),
+        Operands::PairData => data_byte(vec![Operand::Pair(opa >> 1), Operand::Byte(words[1])]),
+        Operands::Pair => Some((vec![opcode], vec![Operand::Pair(opa >> 1)])),
+        Operands::Address => data_byte(vec![Operand::Address(((opa as u16) << 8) | words[1] as u16)]),
+        Operands::RegisterAddress =>
+            data_byte(vec![Operand::Register(opa), Operand::Address(in_page(words[1]))]),
+        Operands::Register => Some((vec![opcode], vec![Operand::Register(opa)])),
+        Operands::Data => Some((vec![opcode], vec![Operand::Data(opa)])),
+        Operands::None => Some((vec![opcode], Vec::new())),
+    }.unwrap_or((vec![opcode], Vec::new()));
+
+    Instruction { address, bytes, mnemonic, operands }
+}
+
+// Decode a whole image loaded at `origin`.
+pub fn disassemble(model: Model, rom: &[u8], origin: u16) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    while offset < rom.len() {
+        let address = origin.wrapping_add(offset as u16) & 0xfff;
+        let instruction = decode(model, &rom[offset..], address);
+        offset += instruction.bytes.len();
+        instructions.push(instruction);
+    }
+
+    instructions
+}
+
+// Jump targets that land on an instruction in `instructions`.
+pub fn labels(instructions: &[Instruction]) -> BTreeSet<u16> {
+    let starts: BTreeSet<u16> = instructions.iter().map(|i| i.address).collect();
+    instructions.iter()
+        .filter_map(|i| i.target())
+        .filter(|a| starts.contains(a))
+        .collect()
+}
+
+// Same layout as the assembler listings, header and all:
+//   0005: L005
+//   0005:        JUN L005        40 05
+pub fn listing(instructions: &[Instruction]) -> String {
+    let labels = labels(instructions);
+    let mut s = String::from("pass 1: done\npass 2\n\n");
+
+    for i in instructions {
+        if labels.contains(&i.address) {
+            s += &format!("{:04X}: {}\n", i.address, label(i.address));
+        }
+        let bytes = i.bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+        s += &format!("{:04X}:        {:<16}{}\n", i.address, i.text(Some(&labels)), bytes);
+    }
+
+    s += "done.\n";
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(model: Model, words: &[u8], address: u16) -> String {
+        decode(model, words, address).to_string()
+    }
+
+    #[test]
+    fn decode_operands() {
+        assert_eq!(text(Model::I4004, &[0x20, 0xa2], 0), "FIM P0 $A2");
+        assert_eq!(text(Model::I4004, &[0x33], 0), "JIN P1");
+        assert_eq!(text(Model::I4004, &[0x81], 0), "ADD R1");
+        assert_eq!(text(Model::I4004, &[0xd9], 0), "LDM $09");
+        assert_eq!(text(Model::I4004, &[0x54, 0x21], 0), "JMS $421");
+        assert_eq!(text(Model::I4004, &[0xe0], 0), "WRM");
+        assert_eq!(text(Model::I4004, &[0x1c, 0x10], 0), "JCN AN $010");
+        assert_eq!(text(Model::I4004, &[0x13, 0x10], 0), "JCN $3 $010");
+    }
+
+    #[test]
+    fn decode_in_page() {
+        // JCN and ISZ jump within the page after their second word
+        assert_eq!(text(Model::I4004, &[0x71, 0x20], 0x1fe), "ISZ R1 $220");
+        assert_eq!(text(Model::I4004, &[0x71, 0x20], 0x1fd), "ISZ R1 $120");
+    }
+
+    #[test]
+    fn decode_db() {
+        // unused opcodes, and a two word instruction cut short
+        assert_eq!(text(Model::I4004, &[0xfe], 0), "DB  $FE");
+        assert_eq!(text(Model::I4040, &[0x0f], 0), "DB  $0F");
+        assert_eq!(text(Model::I4004, &[0x01], 0), "NOP");
+        assert_eq!(text(Model::I4040, &[0x01], 0), "HLT");
+        let cut = decode(Model::I4004, &[0x40], 0);
+        assert_eq!((cut.mnemonic, cut.bytes), ("DB", vec![0x40]));
+    }
+
+    #[test]
+    fn disassemble_wraps() {
+        let instructions = disassemble(Model::I4004, &[0xd1, 0x40, 0x00, 0xe0], 0xffe);
+        let addresses: Vec<u16> = instructions.iter().map(|i| i.address).collect();
+        assert_eq!(addresses, [0xffe, 0xfff, 0x001]);
+        assert_eq!(instructions[1].bytes, [0x40, 0x00]);
+    }
+
+    #[test]
+    fn listing_layout() {
+        let instructions = disassemble(Model::I4004, &[0x20, 0xa2, 0xa0, 0x40, 0x03], 0);
+        assert_eq!(listing(&instructions), "\
+pass 1: done
+pass 2
+
+0000:        FIM P0 $A2      20 A2
+0002:        LD  R0          A0
+0003: L003
+0003:        JUN L003        40 03
+done.
+");
+    }
+}
@@ -0,0 +1,276 @@
+// Character display. Text is ASCII, sent or stored a nibble at a time with
+// the high nibble first, so one character takes two 4 bit chars. Control
+// characters show as spaces.
+//
+// There are two ways to drive it:
+//
+// RamDisplay maps 4002 registers onto rows of the screen. The 16 main chars
+// of a register are 8 characters, the 4 status chars 2 more if they are
+// included. The program just writes to RAM and the display is refreshed
+// from it.
+//
+// PortDisplay is a peripheral on a ROM or RAM output port that takes a
+// stream of characters like a terminal. The first nibble written after
+// reset is a high nibble. Line feed moves down a line, carriage return to
+// the start of the line and form feed clears the screen. Writing past the
+// bottom scrolls.
+
+use std::fmt;
+use hardware::Hardware;
+use peripheral::Peripheral;
+
+const LINE_FEED: u8 = 0x0a;
+const FORM_FEED: u8 = 0x0c;
+const CARRIAGE_RETURN: u8 = 0x0d;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screen {
+    width: usize,
+    height: usize,
+    cells: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(width: usize, height: usize) -> Screen {
+        Screen {
+            width,
+            height,
+            cells: vec![b' '; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get(&self, row: usize, column: usize) -> u8 {
+        self.cells[row * self.width + column]
+    }
+
+    pub fn set(&mut self, row: usize, column: usize, value: u8) {
+        self.cells[row * self.width + column] = printable(value);
+    }
+
+    pub fn clear(&mut self) {
+        for cell in &mut self.cells {
+            *cell = b' ';
+        }
+    }
+
+    fn scroll(&mut self) {
+        self.cells.drain(..self.width);
+        self.cells.extend(vec![b' '; self.width]);
+    }
+
+    // Rows with trailing spaces trimmed, one line each. For tests and
+    // capturing output.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for row in self.cells.chunks(self.width) {
+            text.push_str(String::from_utf8_lossy(row).trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    // The whole screen in a box for a terminal. Move the cursor up
+    // render_height() lines first to draw over the last one.
+    pub fn render(&self) -> String {
+        let border = format!("+{}+\n", "-".repeat(self.width));
+        let mut out = border.clone();
+        for row in self.cells.chunks(self.width) {
+            out.push('|');
+            out.push_str(&String::from_utf8_lossy(row));
+            out.push_str("|\n");
+        }
+        out.push_str(&border);
+        out
+    }
+
+    pub fn render_height(&self) -> usize {
+        self.height + 2
+    }
+}
+
+impl fmt::Display for Screen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text())
+    }
+}
+
+fn printable(value: u8) -> u8 {
+    if (0x20..0x7f).contains(&value) { value } else { b' ' }
+}
+
+// A RAM register shown as one row of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Row {
+    pub bank: u8,
+    pub chip: u8,
+    pub register: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct RamDisplay {
+    rows: Vec<Row>,
+    status: bool,
+    screen: Screen,
+}
+
+impl RamDisplay {
+    // With `status` the status chars add 2 characters to the end of each
+    // row.
+    pub fn new(rows: Vec<Row>, status: bool) -> RamDisplay {
+        let width = if status { 10 } else { 8 };
+        let height = rows.len();
+        RamDisplay {
+            rows,
+            status,
+            screen: Screen::new(width, height),
+        }
+    }
+
+    // Registers on chips that aren't fitted show as blank.
+    pub fn refresh(&mut self, hardware: &Hardware) -> &Screen {
+        for (y, row) in self.rows.iter().enumerate() {
+            let mut nibbles = Vec::with_capacity(20);
+            for character in 0..16 {
+                nibbles.push(hardware.ram_read_char(row.bank, row.chip, row.register, character).unwrap_or(0));
+            }
+            if self.status {
+                for status in 0..4 {
+                    nibbles.push(hardware.ram_read_status(row.bank, row.chip, row.register, status).unwrap_or(0));
+                }
+            }
+            for (x, pair) in nibbles.chunks(2).enumerate() {
+                self.screen.set(y, x, (pair[0] << 4) | pair[1]);
+            }
+        }
+        &self.screen
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PortDisplay {
+    screen: Screen,
+    row: usize,
+    column: usize,
+    high: Option<u8>, // first nibble of a character
+    changed: bool,
+}
+
+impl PortDisplay {
+    pub fn new(width: usize, height: usize) -> PortDisplay {
+        PortDisplay {
+            screen: Screen::new(width, height),
+            row: 0,
+            column: 0,
+            high: None,
+            changed: false,
+        }
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    // True if anything has been shown since the last call.
+    pub fn take_changed(&mut self) -> bool {
+        let changed = self.changed;
+        self.changed = false;
+        changed
+    }
+
+    pub fn put(&mut self, value: u8) {
+        match value {
+            LINE_FEED => self.line_feed(),
+            CARRIAGE_RETURN => self.column = 0,
+            FORM_FEED => {
+                self.screen.clear();
+                self.row = 0;
+                self.column = 0;
+            },
+            _ => {
+                if self.column == self.screen.width() {
+                    self.column = 0;
+                    self.line_feed();
+                }
+                self.screen.set(self.row, self.column, value);
+                self.column += 1;
+            },
+        }
+        self.changed = true;
+    }
+
+    fn line_feed(&mut self) {
+        if self.row + 1 == self.screen.height() {
+            self.screen.scroll();
+        } else {
+            self.row += 1;
+        }
+    }
+}
+
+impl Peripheral for PortDisplay {
+    fn write(&mut self, value: u8, _cycle: u64) {
+        match self.high.take() {
+            Some(high) => self.put((high << 4) | (value & 0b1111)),
+            None => self.high = Some(value & 0b1111),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hardware::Hardware;
+    use super::*;
+
+    #[test]
+    fn screen_text() {
+        let mut screen = Screen::new(4, 2);
+        screen.set(0, 0, b'h');
+        screen.set(0, 1, b'i');
+        screen.set(1, 3, 0x07); // bell shows as a space
+        assert_eq!(screen.text(), "hi\n\n");
+    }
+
+    #[test]
+    fn ram_display() {
+        let mut hardware = Hardware::new(Vec::new()).unwrap();
+        for (character, &nibble) in [0x4, 0x8, 0x6, 0x9].iter().enumerate() {
+            hardware.ram_write_char(0, 1, 2, character as u8, nibble).unwrap();
+        }
+        hardware.ram_write_status(0, 1, 2, 0, 0x2).unwrap();
+        hardware.ram_write_status(0, 1, 2, 1, 0x1).unwrap();
+
+        let rows = vec![Row { bank: 0, chip: 1, register: 2 }, Row { bank: 0, chip: 0, register: 0 }];
+        let mut display = RamDisplay::new(rows, true);
+        assert_eq!(display.refresh(&hardware).text(), "Hi      !\n\n");
+    }
+
+    #[test]
+    fn port_display() {
+        let mut display = PortDisplay::new(3, 2);
+        for &c in b"abcd\rX\nyz\n" {
+            display.write(c >> 4, 0);
+            display.write(c & 0xf, 0);
+        }
+        // line feed keeps the column
+        assert_eq!(display.screen().text(), " yz\n\n");
+        assert!(display.take_changed());
+        assert!(!display.take_changed());
+
+        for &c in b"\x0cok" {
+            display.put(c);
+        }
+        assert_eq!(display.screen().text(), "ok\n\n");
+    }
+}
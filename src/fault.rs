@@ -0,0 +1,81 @@
+// Things that can go wrong while building or running the machine. Real
+// hardware never stops for any of these, it just does something (often
+// useless). What we do instead is chosen per fault by a FaultPolicy.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    BadImageSize { size: usize, max: usize }, // image doesn't fit in ROM
+    NoRomBank { bank: u8 },                   // image loaded into a bank past the last
+    StackUnderflow { address: u16 },          // BBL with nothing to return to
+    UnmappedRamChip { bank: u8, chip: u8 },   // DCL and SRC selected a missing 4002
+    InvalidOpcode { address: u16, opcode: u8 },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::BadImageSize { size, max } =>
+                write!(f, "image is {} bytes, at most {} fit", size, max),
+            Fault::NoRomBank { bank } =>
+                write!(f, "there is no ROM bank {}", bank),
+            Fault::StackUnderflow { address } =>
+                write!(f, "program counter stack underflow at {:03x}", address),
+            Fault::UnmappedRamChip { bank, chip } =>
+                write!(f, "no RAM chip {} fitted in bank {}", chip, bank),
+            Fault::InvalidOpcode { address, opcode } =>
+                write!(f, "unrecognized instruction {:02x} at {:03x}", opcode, address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Trap,    // stop and report the fault
+    Warn,    // print a warning then carry on as the hardware would
+    Emulate, // quietly carry on as the hardware would
+}
+
+// What the hardware does in each case:
+//   stack underflow   - the stack pointer wraps and the pc is loaded from a
+//                       stale stack register. We don't track stale
+//                       contents so return to 000, what reset leaves there.
+//   unmapped RAM chip - nothing drives the data bus. Reads give 0, writes
+//                       are lost.
+//   invalid opcode    - executes as a NOP.
+// Image size and ROM bank faults happen before anything runs so are always
+// reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultPolicy {
+    pub stack_underflow: Policy,
+    pub unmapped_ram_chip: Policy,
+    pub invalid_opcode: Policy,
+}
+
+impl FaultPolicy {
+    // Use the same policy for everything.
+    pub fn all(policy: Policy) -> FaultPolicy {
+        FaultPolicy {
+            stack_underflow: policy,
+            unmapped_ram_chip: policy,
+            invalid_opcode: policy,
+        }
+    }
+
+    pub fn policy(&self, fault: &Fault) -> Policy {
+        match *fault {
+            Fault::BadImageSize { .. }    => Policy::Trap,
+            Fault::NoRomBank { .. }       => Policy::Trap,
+            Fault::StackUnderflow { .. }  => self.stack_underflow,
+            Fault::UnmappedRamChip { .. } => self.unmapped_ram_chip,
+            Fault::InvalidOpcode { .. }   => self.invalid_opcode,
+        }
+    }
+}
+
+impl Default for FaultPolicy {
+    fn default() -> FaultPolicy {
+        FaultPolicy::all(Policy::Trap)
+    }
+}
@@ -1,62 +1,577 @@
 
 // This will contain all the random hardware required. ROM, RAM, etc. Will
-// probably emulate this at a high level. For example I won't emulate
-// individual ROM/RAM chips. I'll just emulate the entire memory space and
-// bank switching will just be an offset or something.
+// probably emulate this at a high level. I'll just emulate the entire memory
+// space and bank switching will just be an offset or something.
 
 // The 4004 can control 16 4001 ROMs. Each ROM contains 256 x 8bit words.
-// 16 * 256 x 8bit words = 4096 x 8bit words.
+// 16 * 256 x 8bit words = 4096 x 8bit words. The high nibble of the address
+// picks the chip. Each chip also has its own IO port, picked by the chip
+// number sent with SRC.
+// The 4040 has two CM-ROM lines so can address a second bank of the same
+// size, selected with DB0/DB1.
+
+// 4002 RAMs are in up to 8 banks of 4. DCL picks the bank by choosing which
+// CM-RAM lines are driven, SRC picks the chip within the bank.
+use std::fmt;
+use std::io::{Read, Write};
+use fault::Fault;
+use peripheral::Peripheral;
+use program_ram::ProgramRam;
 use ram::Ram;
-use rom::Rom;
+use rom;
+use rom::{PortConfig, Rom};
+use savestate;
+use savestate::SaveStateError;
+use signal::TestSignal;
 
 const ROM_SIZE: usize = 4096;
+pub const ROM_CHIPS: usize = 16;
+pub const ROM_BANKS: usize = 2;
+pub const RAM_CHIPS: usize = 4;
+pub const RAM_BANKS: usize = 8;
+
+// Everything the CPU can write to, as far as the CPU can address it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    RamChar { bank: u8, chip: u8, register: u8, character: u8 },
+    RamStatus { bank: u8, chip: u8, register: u8, status: u8 },
+    RamOutput { bank: u8, chip: u8 },
+    RomPort { bank: u8, chip: u8 },
+    ProgramRam { address: u16, half: u8 }, // half 0 is the high nibble
+    ProgramRamHalf,                        // the 4008's nibble flip-flop
+    ProgramRamReadBack,                    // nibble copied for RDR
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Location::RamChar { bank, chip, register, character } =>
+                write!(f, "{}ram{}.r{}.c{:x}", bank_prefix(bank), chip, register, character),
+            Location::RamStatus { bank, chip, register, status } =>
+                write!(f, "{}ram{}.r{}.s{}", bank_prefix(bank), chip, register, status),
+            Location::RamOutput { bank, chip } => write!(f, "{}ram{}.out", bank_prefix(bank), chip),
+            Location::RomPort { bank: 0, chip } => write!(f, "rom{:x}.io", chip),
+            Location::RomPort { bank, chip } => write!(f, "bank{}.rom{:x}.io", bank, chip),
+            Location::ProgramRam { address, half } =>
+                write!(f, "pram.{:03x}{}", address, if half == 0 { "h" } else { "l" }),
+            Location::ProgramRamHalf => write!(f, "pram.half"),
+            Location::ProgramRamReadBack => write!(f, "pram.read"),
+        }
+    }
+}
+
+// Bank 0 is left out to keep the common case short.
+fn bank_prefix(bank: u8) -> String {
+    match bank {
+        0 => String::new(),
+        _ => format!("bank{}.", bank),
+    }
+}
+
+// A 4002 output port changing level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputChange {
+    pub bank: u8,
+    pub chip: u8,
+    pub old: u8,
+    pub new: u8,
+    pub cycle: u64,
+}
+
+pub type OutputSubscriber = Box<dyn FnMut(OutputChange)>;
+
+struct Subscription {
+    id: usize,
+    bank: u8,
+    chip: u8,
+    subscriber: OutputSubscriber,
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "subscription {} to {}", self.id, Location::RamOutput { bank: self.bank, chip: self.chip })
+    }
+}
 
 #[derive(Debug)]
 pub struct Hardware {
-    rom: Rom,
-    ram: Ram
+    rom: Vec<Rom>, // ROM_CHIPS per bank, bank 0 first
+    rom_peripherals: Vec<Option<Box<dyn Peripheral>>>, // same order as rom
+    program_ram: Option<ProgramRam>, // in place of some of bank 0
+    ram: Vec<Ram>, // RAM_CHIPS per bank, bank 0 first
+    ram_fitted: [u8; RAM_BANKS], // chips fitted in each bank, from chip 0 up
+    ram_peripherals: Vec<Option<Box<dyn Peripheral>>>, // same order as ram
+
+    // Called when a RAM output port changes.
+    subscriptions: Vec<Subscription>,
+    next_subscription: usize,
+
+    // Machine cycle of the access being made. The CPU keeps this up to date.
+    cycle: u64,
+
+    // TEST input pin. Driven by test_source if one is attached.
+    test: bool,
+    test_source: Option<Box<dyn TestSignal>>,
+
+    // 4040 INT input. true when an interrupt is being requested. Driven by
+    // interrupt_source if one is attached.
+    interrupt: bool,
+    interrupt_source: Option<Box<dyn TestSignal>>,
 }
 
 impl Hardware {
-    pub fn new(rom: Vec<u8>) -> Hardware {
-        assert!(rom.len() <= ROM_SIZE);
-        Hardware {
-            rom: Rom::new(rom),
-            ram: Ram::new()
+    pub fn new(rom: Vec<u8>) -> Result<Hardware, Fault> {
+        let mut chips = load_chips(rom)?;
+        chips.extend(load_chips(Vec::new())?);
+        Ok(Hardware {
+            rom: chips,
+            rom_peripherals: (0..ROM_BANKS * ROM_CHIPS).map(|_| None).collect(),
+            program_ram: None,
+            ram: vec![Ram::new(); RAM_BANKS * RAM_CHIPS],
+            ram_fitted: [RAM_CHIPS as u8; RAM_BANKS],
+            ram_peripherals: (0..RAM_BANKS * RAM_CHIPS).map(|_| None).collect(),
+            subscriptions: Vec::new(),
+            next_subscription: 0,
+            cycle: 0,
+            test: false,
+            test_source: None,
+            interrupt: false,
+            interrupt_source: None,
+        })
+    }
+
+    // Load an image into one of the 4040's ROM banks. Bank 0 is the one
+    // given to new().
+    pub fn load_rom_bank(&mut self, bank: u8, rom: Vec<u8>) -> Result<(), Fault> {
+        if bank as usize >= ROM_BANKS {
+            return Err(Fault::NoRomBank { bank });
+        }
+        let first = bank as usize * ROM_CHIPS;
+        for (chip, new) in load_chips(rom)?.into_iter().enumerate() {
+            self.rom[first + chip] = new;
+        }
+        Ok(())
+    }
+
+    // Fit `chips` RAMs in `bank`, chips 0 up. Every bank is full to start
+    // with. Contents of removed chips are kept but can't be reached.
+    pub fn fit_ram(&mut self, bank: u8, chips: u8) {
+        assert!((bank as usize) < RAM_BANKS && (chips as usize) <= RAM_CHIPS);
+        self.ram_fitted[bank as usize] = chips;
+    }
+
+    pub fn ram_fitted(&self, bank: u8) -> u8 {
+        self.ram_fitted[bank as usize]
+    }
+
+    // Every RAM chip whether fitted or not, ROM IO ports and input pin
+    // levels. A TEST source's position in its waveform isn't saved, it is
+    // driven by the cycle count anyway.
+    pub fn save_state(&self, w: &mut dyn Write) -> Result<(), SaveStateError> {
+        for ram in &self.ram {
+            ram.save_state(w)?;
+        }
+        for rom in &self.rom {
+            rom.save_state(w)?;
+        }
+        savestate::write_bool(w, self.test)?;
+        savestate::write_bool(w, self.interrupt)?;
+        savestate::write_bool(w, self.program_ram.is_some())?;
+        if let Some(ref program_ram) = self.program_ram {
+            program_ram.save_state(w)?;
+        }
+        Ok(())
+    }
+
+    // Nothing changes unless the whole state loads.
+    pub fn load_state(&mut self, r: &mut dyn Read) -> Result<(), SaveStateError> {
+        let mut ram = Vec::with_capacity(RAM_BANKS * RAM_CHIPS);
+        for _ in 0..RAM_BANKS * RAM_CHIPS {
+            ram.push(Ram::load_state(r)?);
+        }
+        let mut ports = [(0, 0, 0); ROM_BANKS * ROM_CHIPS];
+        for port in &mut ports {
+            *port = Rom::read_state(r)?;
+        }
+        let test = savestate::read_bool(r, "TEST pin")?;
+        let interrupt = savestate::read_bool(r, "INT pin")?;
+        let program_ram = match (savestate::read_bool(r, "program RAM")?, &self.program_ram) {
+            (true, Some(program_ram)) => Some(program_ram.load_state(r)?),
+            (false, None) => None,
+            _ => return Err(SaveStateError::Corrupt("program RAM")),
+        };
+
+        self.ram = ram;
+        for (rom, &port) in self.rom.iter_mut().zip(ports.iter()) {
+            rom.apply_state(port);
+        }
+        self.test = test;
+        self.interrupt = interrupt;
+        self.program_ram = program_ram;
+        Ok(())
+    }
+
+    // Put program RAM in place of some of bank 0's ROMs. It starts with
+    // whatever the ROM image had there.
+    pub fn fit_program_ram(&mut self, mut program_ram: ProgramRam) {
+        for address in program_ram.start()..program_ram.end() {
+            program_ram.write_word(address, self.rom_read_word(0, address));
+        }
+        self.program_ram = Some(program_ram);
+    }
+
+    pub fn program_ram(&self) -> Option<&ProgramRam> {
+        self.program_ram.as_ref()
+    }
+
+    pub fn program_ram_mut(&mut self) -> Option<&mut ProgramRam> {
+        self.program_ram.as_mut()
+    }
+
+    pub fn set_cycle(&mut self, cycle: u64) {
+        self.cycle = cycle;
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    // Call `subscriber` whenever the output port of RAM `chip` in `bank`
+    // changes. Returns an id for unsubscribe().
+    pub fn subscribe_ram_output(&mut self, bank: u8, chip: u8, subscriber: OutputSubscriber) -> usize {
+        let id = self.next_subscription;
+        self.next_subscription += 1;
+        self.subscriptions.push(Subscription { id, bank, chip, subscriber });
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: usize) -> bool {
+        let before = self.subscriptions.len();
+        self.subscriptions.retain(|s| s.id != id);
+        self.subscriptions.len() != before
+    }
+
+    // Hold the INT pin at a fixed level. Detaches any interrupt source.
+    pub fn set_interrupt(&mut self, level: bool) {
+        self.interrupt = level;
+        self.interrupt_source = None;
+    }
+
+    // Anything that can drive TEST can drive INT too.
+    pub fn attach_interrupt(&mut self, source: Box<dyn TestSignal>) {
+        self.interrupt_source = Some(source);
+    }
+
+    pub fn interrupt(&mut self, cycle: u64) -> bool {
+        match self.interrupt_source {
+            Some(ref mut source) => source.level(cycle),
+            None => self.interrupt,
+        }
+    }
+
+    // Hold the TEST pin at a fixed level. Detaches any test source.
+    pub fn set_test(&mut self, level: bool) {
+        self.test = level;
+        self.test_source = None;
+    }
+
+    pub fn attach_test(&mut self, source: Box<dyn TestSignal>) {
+        self.test_source = Some(source);
+    }
+
+    pub fn test(&mut self, cycle: u64) -> bool {
+        match self.test_source {
+            Some(ref mut source) => source.level(cycle),
+            None => self.test,
+        }
+    }
+
+    fn rom(&self, bank: u8, chip: u8) -> &Rom {
+        &self.rom[bank as usize * ROM_CHIPS + chip as usize]
+    }
+
+    fn rom_mut(&mut self, bank: u8, chip: u8) -> &mut Rom {
+        &mut self.rom[bank as usize * ROM_CHIPS + chip as usize]
+    }
+
+    pub fn rom_read_word(&self, bank: u8, address: u16) -> u8 {
+        match self.program_ram {
+            Some(ref program_ram) if bank == 0 && program_ram.contains(address) =>
+                program_ram.read_word(address),
+            _ => self.rom(bank, (address >> 8) as u8).read_word(address as u8),
+        }
+    }
+
+    // Address of the program RAM word WPM/RPM would access with SRC sending
+    // `low`. None if there is no program RAM there.
+    pub fn program_ram_address(&self, low: u8) -> Option<u16> {
+        let program_ram = self.program_ram.as_ref()?;
+        let high = self.rom(0, program_ram.address_port()).read_latch();
+        let address = ((high as u16) << 8) | low as u16;
+        if program_ram.contains(address) { Some(address) } else { None }
+    }
+
+    // Bit 0 of the control port.
+    pub fn program_ram_write_enabled(&self) -> bool {
+        match self.program_ram {
+            Some(ref program_ram) => self.rom(0, program_ram.control_port()).read_latch() & 1 != 0,
+            None => false,
+        }
+    }
+
+    // Replaces anything already attached to the port.
+    pub fn attach_rom_peripheral(&mut self, bank: u8, chip: u8, peripheral: Box<dyn Peripheral>) {
+        self.rom_peripherals[bank as usize * ROM_CHIPS + chip as usize] = Some(peripheral);
+    }
+
+    pub fn detach_rom_peripheral(&mut self, bank: u8, chip: u8) -> Option<Box<dyn Peripheral>> {
+        self.rom_peripherals[bank as usize * ROM_CHIPS + chip as usize].take()
+    }
+
+    // Replaces anything already attached to the port. The chip doesn't have
+    // to be fitted, the peripheral just never hears anything if it isn't.
+    pub fn attach_ram_peripheral(&mut self, bank: u8, chip: u8, peripheral: Box<dyn Peripheral>) {
+        self.ram_peripherals[bank as usize * RAM_CHIPS + chip as usize] = Some(peripheral);
+    }
+
+    pub fn detach_ram_peripheral(&mut self, bank: u8, chip: u8) -> Option<Box<dyn Peripheral>> {
+        self.ram_peripherals[bank as usize * RAM_CHIPS + chip as usize].take()
+    }
+
+    pub fn rom_read_port(&mut self, bank: u8, chip: u8) -> u8 {
+        let index = bank as usize * ROM_CHIPS + chip as usize;
+        if let Some(ref mut peripheral) = self.rom_peripherals[index] {
+            match peripheral.read(self.cycle) {
+                Some(levels) => self.rom[index].drive_inputs(levels, 0b1111),
+                None => self.rom[index].drive_inputs(0, 0),
+            }
         }
+        self.rom[index].read_port()
     }
 
-    pub fn rom_read_word(&self, address: u16) -> u8 {
-        self.rom.read_word(address as u8)
+    // What RDR from ROM port `chip` reads instead of the port, if it is the
+    // program RAM address port with writing disabled.
+    pub fn program_ram_read_back(&self, bank: u8, chip: u8) -> Option<u8> {
+        let program_ram = self.program_ram.as_ref()?;
+        if bank != 0 || chip != program_ram.address_port() || self.program_ram_write_enabled() {
+            return None;
+        }
+        Some(program_ram.read_back())
     }
 
-    pub fn rom_read_port(&self, _chip: u8) -> u8 {
-        self.rom.read_port()
+    pub fn rom_write_port(&mut self, bank: u8, chip: u8, value: u8) {
+        let index = bank as usize * ROM_CHIPS + chip as usize;
+        self.rom[index].write_port(value);
+        if let Some(ref mut peripheral) = self.rom_peripherals[index] {
+            peripheral.write(self.rom[index].outputs(), self.cycle);
+        }
+    }
+
+    // Mask options for one chip's IO pins.
+    pub fn configure_rom_port(&mut self, bank: u8, chip: u8, config: PortConfig) {
+        self.rom_mut(bank, chip).configure(config)
+    }
+
+    pub fn rom_port_config(&self, bank: u8, chip: u8) -> PortConfig {
+        self.rom(bank, chip).config()
     }
 
-    pub fn rom_write_port(&mut self, _chip: u8, value: u8) {
-        self.rom.write_port(value)
+    // Levels on a chip's output pins, as seen from outside.
+    pub fn rom_port_outputs(&self, bank: u8, chip: u8) -> u8 {
+        self.rom(bank, chip).outputs()
     }
 
+    // Drive a chip's input pins from outside. See Rom::drive_inputs.
+    pub fn rom_drive_inputs(&mut self, bank: u8, chip: u8, levels: u8, mask: u8) {
+        self.rom_mut(bank, chip).drive_inputs(levels, mask)
+    }
 
-    // only one chip at the moment
-    pub fn ram_read_char(&self, _chip: u8, register: u8, character: u8) -> u8 {
-        self.ram.read_char(register, character)
+    fn ram_index(&self, bank: u8, chip: u8) -> Result<usize, Fault> {
+        if (bank as usize) < RAM_BANKS && chip < self.ram_fitted[bank as usize] {
+            Ok(bank as usize * RAM_CHIPS + chip as usize)
+        } else {
+            Err(Fault::UnmappedRamChip { bank, chip })
+        }
     }
 
-    pub fn ram_write_char(&mut self, _chip: u8, register: u8, character: u8, value: u8) {
-        self.ram.write_char(register, character, value)
+    fn ram(&self, bank: u8, chip: u8) -> Result<&Ram, Fault> {
+        let index = self.ram_index(bank, chip)?;
+        Ok(&self.ram[index])
     }
 
-    pub fn ram_read_status(&self, _chip: u8, register: u8, status: u8) -> u8 {
-        self.ram.read_status(register, status)
+    fn ram_mut(&mut self, bank: u8, chip: u8) -> Result<&mut Ram, Fault> {
+        let index = self.ram_index(bank, chip)?;
+        Ok(&mut self.ram[index])
     }
 
-    pub fn ram_write_status(&mut self, _chip: u8, register: u8, status: u8, value: u8) {
-        self.ram.write_status(register, status, value)
+    pub fn ram_read_char(&self, bank: u8, chip: u8, register: u8, character: u8) -> Result<u8, Fault> {
+        Ok(self.ram(bank, chip)?.read_char(register, character))
     }
 
-    pub fn ram_write_output(&mut self, _chip: u8, value: u8) {
-        self.ram.write_output(value);
+    pub fn ram_write_char(&mut self, bank: u8, chip: u8, register: u8, character: u8, value: u8) -> Result<(), Fault> {
+        self.ram_mut(bank, chip)?.write_char(register, character, value);
+        Ok(())
+    }
+
+    pub fn ram_read_status(&self, bank: u8, chip: u8, register: u8, status: u8) -> Result<u8, Fault> {
+        Ok(self.ram(bank, chip)?.read_status(register, status))
+    }
+
+    pub fn ram_write_status(&mut self, bank: u8, chip: u8, register: u8, status: u8, value: u8) -> Result<(), Fault> {
+        self.ram_mut(bank, chip)?.write_status(register, status, value);
+        Ok(())
+    }
+
+    pub fn ram_read_output(&self, bank: u8, chip: u8) -> Result<u8, Fault> {
+        Ok(self.ram(bank, chip)?.read_output())
+    }
+
+    pub fn ram_write_output(&mut self, bank: u8, chip: u8, value: u8) -> Result<(), Fault> {
+        let index = self.ram_index(bank, chip)?;
+        let old = self.ram[index].read_output();
+        self.ram[index].write_output(value);
+
+        if let Some(ref mut peripheral) = self.ram_peripherals[index] {
+            peripheral.write(value, self.cycle);
+        }
+
+        if old != value {
+            let change = OutputChange { bank, chip, old, new: value, cycle: self.cycle };
+            for s in self.subscriptions.iter_mut().filter(|s| s.bank == bank && s.chip == chip) {
+                (s.subscriber)(change);
+            }
+        }
+        Ok(())
+    }
+
+    // Current contents of `location`. For ROM ports this is what was last
+    // written rather than what RDR would see, so writing it back undoes a
+    // WRR.
+    pub fn read(&self, location: Location) -> Result<u8, Fault> {
+        match location {
+            Location::RamChar { bank, chip, register, character } =>
+                self.ram_read_char(bank, chip, register, character),
+            Location::RamStatus { bank, chip, register, status } =>
+                self.ram_read_status(bank, chip, register, status),
+            Location::RamOutput { bank, chip } => self.ram_read_output(bank, chip),
+            Location::RomPort { bank, chip } => Ok(self.rom(bank, chip).read_latch()),
+            // Nothing there reads as 0, like a missing ROM
+            Location::ProgramRam { address, half } => Ok(match self.program_ram {
+                Some(ref p) if p.contains(address) => p.read_nibble(address, half),
+                _ => 0,
+            }),
+            Location::ProgramRamHalf => Ok(self.program_ram.as_ref().map_or(0, |p| p.half())),
+            Location::ProgramRamReadBack => Ok(self.program_ram.as_ref().map_or(0, |p| p.read_back())),
+        }
+    }
+
+    pub fn write(&mut self, location: Location, value: u8) -> Result<(), Fault> {
+        match location {
+            Location::RamChar { bank, chip, register, character } =>
+                self.ram_write_char(bank, chip, register, character, value),
+            Location::RamStatus { bank, chip, register, status } =>
+                self.ram_write_status(bank, chip, register, status, value),
+            Location::RamOutput { bank, chip } => self.ram_write_output(bank, chip, value),
+            Location::RomPort { bank, chip } => {
+                self.rom_write_port(bank, chip, value);
+                Ok(())
+            },
+            // and writes to nothing go nowhere
+            Location::ProgramRam { address, half } => {
+                if let Some(ref mut p) = self.program_ram {
+                    if p.contains(address) {
+                        p.write_nibble(address, half, value);
+                    }
+                }
+                Ok(())
+            },
+            Location::ProgramRamHalf => {
+                if let Some(ref mut p) = self.program_ram {
+                    p.set_half(value);
+                }
+                Ok(())
+            },
+            Location::ProgramRamReadBack => {
+                if let Some(ref mut p) = self.program_ram {
+                    p.set_read_back(value);
+                }
+                Ok(())
+            },
+        }
+    }
+
+    // Put `value` back in `location` for an undo. Unlike write, peripherals
+    // and output subscribers aren't told, as what they did with the write
+    // being undone can't be taken back.
+    pub fn restore(&mut self, location: Location, value: u8) -> Result<(), Fault> {
+        match location {
+            Location::RamOutput { bank, chip } => {
+                self.ram_mut(bank, chip)?.write_output(value);
+                Ok(())
+            },
+            Location::RomPort { bank, chip } => {
+                self.rom_mut(bank, chip).write_port(value);
+                Ok(())
+            },
+            _ => self.write(location, value),
+        }
+    }
+}
+
+// Split an image into ROM_CHIPS chips. Chips past the end of the image are
+// left blank.
+fn load_chips(image: Vec<u8>) -> Result<Vec<Rom>, Fault> {
+    if image.len() > ROM_SIZE {
+        return Err(Fault::BadImageSize { size: image.len(), max: ROM_SIZE });
+    }
+
+    let mut chips = Vec::with_capacity(ROM_CHIPS);
+    for chip in 0..ROM_CHIPS {
+        let start = (chip * rom::ROM_SIZE).min(image.len());
+        let end = ((chip + 1) * rom::ROM_SIZE).min(image.len());
+        chips.push(Rom::new(image[start..end].to_vec())?);
+    }
+    Ok(chips)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use fault::Fault;
+    use super::*;
+
+    #[test]
+    fn load_rom_bank_past_the_last() {
+        let mut hardware = Hardware::new(vec![0xd5]).unwrap();
+        hardware.load_rom_bank(1, vec![0xd7]).unwrap();
+        assert_eq!(hardware.rom_read_word(1, 0), 0xd7);
+        assert_eq!(hardware.load_rom_bank(2, Vec::new()), Err(Fault::NoRomBank { bank: 2 }));
+    }
+
+    #[test]
+    fn ram_output_subscriptions() {
+        let mut hardware = Hardware::new(Vec::new()).unwrap();
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let seen = changes.clone();
+        let id = hardware.subscribe_ram_output(1, 2, Box::new(move |change| seen.borrow_mut().push(change)));
+
+        hardware.set_cycle(10);
+        hardware.ram_write_output(1, 2, 5).unwrap();
+        hardware.set_cycle(12);
+        hardware.ram_write_output(1, 2, 5).unwrap(); // no change
+        hardware.ram_write_output(0, 2, 6).unwrap(); // another chip
+        hardware.set_cycle(20);
+        hardware.ram_write_output(1, 2, 9).unwrap();
+        assert_eq!(*changes.borrow(), [
+            OutputChange { bank: 1, chip: 2, old: 0, new: 5, cycle: 10 },
+            OutputChange { bank: 1, chip: 2, old: 5, new: 9, cycle: 20 },
+        ]);
+
+        assert!(hardware.unsubscribe(id));
+        assert!(!hardware.unsubscribe(id));
+        hardware.ram_write_output(1, 2, 1).unwrap();
+        assert_eq!(changes.borrow().len(), 2);
+        assert_eq!(hardware.ram_read_output(1, 2), Ok(1));
     }
 }
@@ -0,0 +1,73 @@
+// Execution history for stepping backwards. Every instruction executed while
+// history is enabled leaves a Delta: the registers as they were before it ran
+// and the memory and port writes it made. Undoing an instruction restores the
+// registers and puts the old values back. Peripherals aren't told about
+// that, so anything they did with the writes stays done. Only the most
+// recent `depth` deltas are kept.
+
+use std::collections::VecDeque;
+use cpu::{MemoryWrite, Registers};
+
+#[derive(Debug, Clone)]
+pub struct Delta {
+    pub registers: Registers,
+    pub writes: Vec<MemoryWrite>,
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    depth: usize,
+    deltas: VecDeque<Delta> // newest at the back
+}
+
+impl History {
+    pub fn new(depth: usize) -> History {
+        History {
+            depth,
+            deltas: VecDeque::with_capacity(depth)
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    // Shrinking drops the oldest deltas.
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.deltas.len() > depth {
+            self.deltas.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+    }
+
+    pub fn push(&mut self, delta: Delta) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.deltas.len() == self.depth {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(delta);
+    }
+
+    pub fn pop(&mut self) -> Option<Delta> {
+        self.deltas.pop_back()
+    }
+
+    // Most recent first.
+    pub fn iter(&self) -> impl Iterator<Item = &Delta> {
+        self.deltas.iter().rev()
+    }
+}
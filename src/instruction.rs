@@ -0,0 +1,120 @@
+// Opcode tables shared by the tracer and anything else that needs to know
+// about instructions without executing them.
+
+use std::str::FromStr;
+use cpu::Model;
+
+// Rough grouping used to filter traces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+    Jump,        // JCN JIN JUN JMS ISZ BBL (and BBS)
+    Register,    // index register and immediate loads: FIM SRC FIN INC ADD ...
+    Io,          // RAM and ROM access, the 0xE group
+    Accumulator, // the 0xF group
+    Control,     // NOP and the 4040 machine control instructions
+}
+
+impl FromStr for Class {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Class, String> {
+        match &*s.to_lowercase() {
+            "jump"        => Ok(Class::Jump),
+            "register"    => Ok(Class::Register),
+            "io"          => Ok(Class::Io),
+            "accumulator" => Ok(Class::Accumulator),
+            "control"     => Ok(Class::Control),
+            _             => Err(format!("unknown instruction class '{}'", s)),
+        }
+    }
+}
+
+// What an instruction takes after the mnemonic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operands {
+    None,
+    Register,         // low nibble
+    Pair,             // bits 1-3
+    Data,             // low nibble
+    PairData,         // FIM, pair then a byte in the second word
+    Address,          // JUN JMS, 12 bits over both words
+    RegisterAddress,  // ISZ, register then an address in the same page
+    ConditionAddress, // JCN, condition then an address in the same page
+}
+
+const OPR_MNEMONICS: [&str; 16] = [
+    "NOP", "JCN", "FIM", "FIN", "JUN", "JMS", "INC", "ISZ",
+    "ADD", "SUB", "LD",  "XCH", "BBL", "LDM", "",    "",
+];
+
+const IO_MNEMONICS: [&str; 16] = [
+    "WRM", "WMP", "WRR", "WPM", "WR0", "WR1", "WR2", "WR3",
+    "SBM", "RDM", "RDR", "ADM", "RD0", "RD1", "RD2", "RD3",
+];
+
+const ACCUMULATOR_MNEMONICS: [&str; 16] = [
+    "CLB", "CLC", "IAC", "CMC", "CMA", "RAL", "RAR", "TCC",
+    "DAC", "TCS", "STC", "DAA", "KBP", "DCL", "",    "",
+];
+
+const CONTROL_MNEMONICS_4040: [&str; 16] = [
+    "NOP", "HLT", "BBS", "LCR", "OR4", "OR5", "AN6", "AN7",
+    "DB0", "DB1", "SB0", "SB1", "EIN", "DIN", "RPM", "",
+];
+
+// JCN, FIM, JUN, JMS and ISZ carry their operand in a second word. FIN
+// takes two cycles but is only one word.
+pub fn is_two_word(opcode: u8) -> bool {
+    match opcode >> 4 {
+        0x1 | 0x4 | 0x5 | 0x7 => true,
+        0x2 => opcode & 0b0001 == 0,
+        _ => false,
+    }
+}
+
+// None for opcodes the model doesn't implement.
+pub fn mnemonic(model: Model, opcode: u8) -> Option<&'static str> {
+    let (opr, opa) = ((opcode >> 4) as usize, (opcode & 0b1111) as usize);
+    let name = match opr {
+        0x0 if model == Model::I4040 => CONTROL_MNEMONICS_4040[opa],
+        0x2 if opa & 0b0001 == 1 => "SRC",
+        0x3 if opa & 0b0001 == 1 => "JIN",
+        0xe => IO_MNEMONICS[opa],
+        0xf => ACCUMULATOR_MNEMONICS[opa],
+        _   => OPR_MNEMONICS[opr],
+    };
+    if name.is_empty() { None } else { Some(name) }
+}
+
+// Opcode for `mnemonic` with the operand bits clear, in either case. 4040
+// instructions are included.
+pub fn opcode(mnemonic: &str) -> Option<u8> {
+    (0..=255).find(|&opcode| {
+        self::mnemonic(Model::I4040, opcode).is_some_and(|m| m.eq_ignore_ascii_case(mnemonic))
+    })
+}
+
+pub fn operands(opcode: u8) -> Operands {
+    match opcode >> 4 {
+        0x1 => Operands::ConditionAddress,
+        0x2 if opcode & 0b0001 == 0 => Operands::PairData,
+        0x2 | 0x3 => Operands::Pair,
+        0x4 | 0x5 => Operands::Address,
+        0x6 | 0x8 | 0x9 | 0xa | 0xb => Operands::Register,
+        0x7 => Operands::RegisterAddress,
+        0xc | 0xd => Operands::Data,
+        _ => Operands::None,
+    }
+}
+
+pub fn class(model: Model, opcode: u8) -> Class {
+    match opcode >> 4 {
+        0x0 if model == Model::I4040 && opcode == 0x02 => Class::Jump, // BBS
+        0x0 => Class::Control,
+        0x1 | 0x4 | 0x5 | 0x7 | 0xc => Class::Jump,
+        0x3 if opcode & 0b0001 == 1 => Class::Jump, // JIN
+        0xe => Class::Io,
+        0xf => Class::Accumulator,
+        _   => Class::Register,
+    }
+}
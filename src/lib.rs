@@ -0,0 +1,23 @@
+// The emulator core. Split out from the binary so tests and tools can drive
+// the CPU directly instead of going through main.
+
+pub mod breakpoint;
+pub mod busicom;
+pub mod clock;
+pub mod cpu;
+pub mod disasm;
+pub mod display;
+pub mod fault;
+pub mod rom;
+pub mod savestate;
+pub mod segment;
+pub mod shifter;
+pub mod ram;
+pub mod hardware;
+pub mod history;
+pub mod instruction;
+pub mod machine;
+pub mod program_ram;
+pub mod peripheral;
+pub mod signal;
+pub mod trace;
@@ -0,0 +1,211 @@
+// Machine description. Things about a particular board that aren't in the
+// ROM image, like the mask options on each 4001's IO pins, how many 4002s
+// are fitted or where program RAM is.
+//
+// One setting per line, # starts a comment:
+//
+//   rom 2 pin 0 input inverted pullup
+//   rom 2 pin 3 output read 1
+//   bank1 rom 0 pin 1 input pulldown
+//   ram bank 1 chips 2
+//   program ram 8-f address e control f
+//
+// Chips are hex. Pins not mentioned keep the default, a direct output that
+// reads back what was written. RAM banks not mentioned have all four chips.
+// Program RAM is given as a range of ROM bank 0 pages, the ports default to
+// e and f.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::str::FromStr;
+use hardware::Hardware;
+use hardware::{RAM_BANKS, RAM_CHIPS};
+use program_ram;
+use program_ram::ProgramRam;
+use rom::{Direction, PinConfig, Pull, NUM_PINS};
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinSetting {
+    pub bank: u8,
+    pub chip: u8,
+    pub pin: u8,
+    pub config: PinConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RamSetting {
+    pub bank: u8,
+    pub chips: u8,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Machine {
+    pub pins: Vec<PinSetting>,
+    pub ram: Vec<RamSetting>,
+    pub program_ram: Option<ProgramRam>,
+}
+
+impl Machine {
+    pub fn from_file(file_name: &str) -> io::Result<Machine> {
+        let mut text = String::new();
+        fs::File::open(file_name)?.read_to_string(&mut text)?;
+        text.parse().map_err(|e: ParseError| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    pub fn apply(&self, hardware: &mut Hardware) {
+        for setting in &self.pins {
+            let mut config = hardware.rom_port_config(setting.bank, setting.chip);
+            config[setting.pin as usize] = setting.config;
+            hardware.configure_rom_port(setting.bank, setting.chip, config);
+        }
+        for setting in &self.ram {
+            hardware.fit_ram(setting.bank, setting.chips);
+        }
+        if let Some(ref program_ram) = self.program_ram {
+            hardware.fit_program_ram(program_ram.clone());
+        }
+    }
+}
+
+impl FromStr for Machine {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Machine, ParseError> {
+        let mut machine = Machine::default();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            let error = |message| ParseError { line: n + 1, message };
+            if words[0].eq_ignore_ascii_case("ram") {
+                machine.ram.push(parse_ram(&words).map_err(error)?);
+            } else if words[0].eq_ignore_ascii_case("program") {
+                machine.program_ram = Some(parse_program_ram(&words).map_err(error)?);
+            } else {
+                machine.pins.push(parse_pin(&words).map_err(error)?);
+            }
+        }
+        Ok(machine)
+    }
+}
+
+fn parse_pin(words: &[&str]) -> Result<PinSetting, String> {
+    let mut words = words.iter().map(|x| x.to_lowercase());
+    let mut word = words.next();
+
+    let mut bank = 0;
+    if let Some(b) = word.as_ref().and_then(|x| x.strip_prefix("bank").map(|x| x.to_string())) {
+        bank = match &*b {
+            "0" => 0,
+            "1" => 1,
+            _ => return Err(format!("bad bank '{}'", b)),
+        };
+        word = words.next();
+    }
+
+    if word.as_deref() != Some("rom") {
+        return Err("expected 'rom'".to_string());
+    }
+    let chip = words.next().and_then(|x| u8::from_str_radix(&x, 16).ok()).filter(|&x| x < 16)
+        .ok_or("expected a chip number 0-f")?;
+
+    if words.next().as_deref() != Some("pin") {
+        return Err("expected 'pin'".to_string());
+    }
+    let pin = words.next().and_then(|x| x.parse::<u8>().ok()).filter(|&x| (x as usize) < NUM_PINS)
+        .ok_or("expected a pin number 0-3")?;
+
+    let direction = match words.next().as_deref() {
+        Some("input") => Direction::Input,
+        Some("output") => Direction::Output,
+        _ => return Err("expected 'input' or 'output'".to_string()),
+    };
+    let mut config = PinConfig { direction, ..PinConfig::default() };
+
+    while let Some(word) = words.next() {
+        match &*word {
+            "inverted" => config.inverted = true,
+            "direct" => config.inverted = false,
+            "pullup" => config.pull = Pull::Up,
+            "pulldown" => config.pull = Pull::Down,
+            "read" => config.read_level = match words.next().as_deref() {
+                Some("0") => Some(false),
+                Some("1") => Some(true),
+                _ => return Err("expected a read level 0 or 1".to_string()),
+            },
+            _ => return Err(format!("unknown option '{}'", word)),
+        }
+    }
+
+    if config.read_level.is_some() && config.direction == Direction::Input {
+        return Err("only outputs can have a fixed read level".to_string());
+    }
+
+    Ok(PinSetting { bank, chip, pin, config })
+}
+
+fn parse_ram(words: &[&str]) -> Result<RamSetting, String> {
+    let mut words = words.iter().skip(1).map(|x| x.to_lowercase());
+
+    if words.next().as_deref() != Some("bank") {
+        return Err("expected 'bank'".to_string());
+    }
+    let bank = words.next().and_then(|x| x.parse::<u8>().ok()).filter(|&x| (x as usize) < RAM_BANKS)
+        .ok_or("expected a bank number 0-7")?;
+
+    if words.next().as_deref() != Some("chips") {
+        return Err("expected 'chips'".to_string());
+    }
+    let chips = words.next().and_then(|x| x.parse::<u8>().ok()).filter(|&x| (x as usize) <= RAM_CHIPS)
+        .ok_or("expected a chip count 0-4")?;
+
+    if let Some(word) = words.next() {
+        return Err(format!("unexpected '{}'", word));
+    }
+
+    Ok(RamSetting { bank, chips })
+}
+
+fn parse_program_ram(words: &[&str]) -> Result<ProgramRam, String> {
+    let mut words = words.iter().skip(1).map(|x| x.to_lowercase());
+
+    if words.next().as_deref() != Some("ram") {
+        return Err("expected 'ram'".to_string());
+    }
+    let pages = words.next().unwrap_or_default();
+    let mut range = pages.splitn(2, '-').map(|x| u8::from_str_radix(x, 16));
+    let (first, last) = match (range.next(), range.next()) {
+        (Some(Ok(first)), Some(Ok(last))) if first <= last && last < 16 => (first, last),
+        (Some(Ok(first)), None) if first < 16 => (first, first),
+        _ => return Err(format!("bad page range '{}'", pages)),
+    };
+
+    let mut address_port = program_ram::DEFAULT_ADDRESS_PORT;
+    let mut control_port = program_ram::DEFAULT_CONTROL_PORT;
+    while let Some(word) = words.next() {
+        let port = words.next().and_then(|x| u8::from_str_radix(&x, 16).ok()).filter(|&x| x < 16)
+            .ok_or("expected a port number 0-f")?;
+        match &*word {
+            "address" => address_port = port,
+            "control" => control_port = port,
+            _ => return Err(format!("unknown option '{}'", word)),
+        }
+    }
+
+    Ok(ProgramRam::new(first, last - first + 1).with_ports(address_port, control_port))
+}
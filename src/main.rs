@@ -1,27 +1,500 @@
+extern crate mcs4;
 
-mod cpu;
-mod rom;
-mod ram;
-mod hardware;
+use mcs4::breakpoint;
+use mcs4::busicom;
+use mcs4::cpu;
+use mcs4::display;
+use mcs4::hardware;
+use mcs4::machine;
+use mcs4::peripheral::Peripheral;
+use mcs4::ram;
+use mcs4::segment;
+use mcs4::signal;
+use mcs4::trace;
 
+use std::cell::RefCell;
 use std::env;
 use std::fs;
-use std::io::Read;
+use std::io::{BufRead, Read, Write};
+use std::process;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+
+// How often a display is redrawn while running, in machine cycles. About 30
+// times a second at the normal clock.
+const REFRESH_CYCLES: u64 = 3000;
+
+// How long the Busicom is left running once there are no more keys, about
+// a second, so it can finish printing.
+const BUSICOM_IDLE_CYCLES: u64 = 100_000;
+
+const PORT_DISPLAY_WIDTH: usize = 40;
+const PORT_DISPLAY_HEIGHT: usize = 8;
+
+enum DisplaySpec {
+    Ram(Vec<display::Row>, bool),
+    Port(hardware::Location),
+    Segments(usize, [hardware::Location; 3], segment::Select),
+}
+
+enum Display {
+    Ram(display::RamDisplay),
+    Port(Rc<RefCell<display::PortDisplay>>),
+    Segments(Rc<RefCell<segment::SevenSegment>>),
+}
+
+impl Display {
+    fn screen(&mut self, cpu: &cpu::CPU) -> display::Screen {
+        match *self {
+            Display::Ram(ref mut d) => d.refresh(cpu.hardware()).clone(),
+            Display::Port(ref d) => d.borrow().screen().clone(),
+            Display::Segments(ref d) => {
+                let mut d = d.borrow_mut();
+                d.frame(cpu.cycles());
+                let lines = d.render();
+                let mut screen = display::Screen::new(lines[0].len(), lines.len());
+                for (row, line) in lines.iter().enumerate() {
+                    for (column, c) in line.bytes().enumerate() {
+                        screen.set(row, column, c);
+                    }
+                }
+                screen
+            },
+        }
+    }
+}
 
 fn main() {
 
-    let rom_file_name = env::args().nth(1).unwrap();
+    let mut model = cpu::Model::I4004;
+    let mut rom_file_name = String::new();
+    let mut cycles = None;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut trace_file = None;
+    let mut trace_format = trace::Format::Text;
+    let mut trace_filter = trace::Filter::default();
+    let mut breakpoints = Vec::new();
+    let mut watchpoints = Vec::new();
+    let mut machine_file = None;
+    let mut display_spec = None;
+    let mut busicom = false;
+    let mut rom_bank1_file_name = None;
+    let mut interrupt = None;
+    let mut test_waveform = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--4040"       => model = cpu::Model::I4040,
+            "--rom-bank1"  => rom_bank1_file_name = Some(args.next().unwrap_or_else(|| usage())),
+            "--interrupt"  => interrupt = Some(parse_waveform(args.next())),
+            "--test-waveform" => test_waveform = Some(parse_waveform(args.next())),
+            "--cycles"     => cycles = Some(parse_arg(args.next())),
+            "--load-state" => load_state = Some(args.next().unwrap_or_else(|| usage())),
+            "--save-state" => save_state = Some(args.next().unwrap_or_else(|| usage())),
+            "--machine"    => machine_file = Some(args.next().unwrap_or_else(|| usage())),
+            "--busicom"    => busicom = true,
+            "--display"    => display_spec = Some(parse_display(args.next())),
+            "--segments"   => {
+                let (digits, ports, select) = parse_segments(args.next());
+                display_spec = Some(DisplaySpec::Segments(digits, ports, select));
+            },
+            "--break"      => breakpoints.push(parse_breakpoint(args.next())),
+            "--watch"      => watchpoints.push(parse_watchpoint(args.next())),
+            "--trace"      => trace_file = Some(args.next().unwrap_or_else(|| usage())),
+            "--trace-format" => trace_format = parse_arg(args.next()),
+            "--trace-pc"   => trace_filter.addresses = Some(parse_range(args.next())),
+            "--trace-class" => trace_filter.classes = args.next().unwrap_or_else(|| usage())
+                                   .split(',').map(|x| parse_arg(Some(x.to_string()))).collect(),
+            _              => rom_file_name = arg,
+        }
+    }
+
+    if rom_file_name.is_empty() {
+        usage();
+    }
+
     let rom = read_rom(&rom_file_name);
 
-    let hardware = hardware::Hardware::new(rom);
-    let mut cpu = cpu::CPU::new(hardware);
-    cpu.run();
+    let mut hardware = hardware::Hardware::new(rom).unwrap_or_else(|fault| {
+        eprintln!("{}: {}", rom_file_name, fault);
+        process::exit(1);
+    });
+    if let Some(file_name) = rom_bank1_file_name {
+        hardware.load_rom_bank(1, read_rom(&file_name)).unwrap_or_else(|fault| {
+            eprintln!("{}: {}", file_name, fault);
+            process::exit(1);
+        });
+    }
+    if let Some(waveform) = interrupt {
+        hardware.attach_interrupt(Box::new(waveform));
+    }
+    if let Some(waveform) = test_waveform {
+        hardware.attach_test(Box::new(waveform));
+    }
+
+    if let Some(file_name) = machine_file {
+        let machine = machine::Machine::from_file(&file_name).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file_name, e);
+            process::exit(1);
+        });
+        machine.apply(&mut hardware);
+    }
+    let busicom = if busicom { Some(busicom::Busicom::attach(&mut hardware)) } else { None };
+
+    let mut display = display_spec.map(|spec| match spec {
+        DisplaySpec::Ram(rows, status) => Display::Ram(display::RamDisplay::new(rows, status)),
+        DisplaySpec::Port(location) => {
+            let d = Rc::new(RefCell::new(display::PortDisplay::new(PORT_DISPLAY_WIDTH, PORT_DISPLAY_HEIGHT)));
+            attach(&mut hardware, location, Box::new(d.clone()));
+            Display::Port(d)
+        },
+        DisplaySpec::Segments(digits, ports, select) => {
+            let d = Rc::new(RefCell::new(segment::SevenSegment::new(digits, select)));
+            let roles = [segment::Role::SegmentsLow, segment::Role::SegmentsHigh, segment::Role::Digit];
+            for (&port, &role) in ports.iter().zip(roles.iter()) {
+                attach(&mut hardware, port, segment::SevenSegment::port(&d, role));
+            }
+            Display::Segments(d)
+        },
+    });
+
+    let mut cpu = cpu::CPU::with_model(hardware, model);
+
+    if let Some(file_name) = load_state {
+        let mut file = fs::File::open(&file_name).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file_name, e);
+            process::exit(1);
+        });
+        cpu.load_state(&mut file).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file_name, e);
+            process::exit(1);
+        });
+    }
+
+    if let Some(ref file_name) = trace_file {
+        let mut tracer = trace::Tracer::to_file(file_name, trace_format).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file_name, e);
+            process::exit(1);
+        });
+        tracer.set_filter(trace_filter);
+        cpu.set_tracer(Some(tracer));
+    }
+
+    for breakpoint in breakpoints {
+        cpu.breakpoints_mut().add_breakpoint(breakpoint);
+    }
+    for watchpoint in watchpoints {
+        cpu.breakpoints_mut().add_watchpoint(watchpoint);
+    }
+
+    let mut shown: Option<display::Screen> = None;
+    let reason = match (cycles, display.as_mut()) {
+        (Some(cycles), _) => cpu.run_for(cycles),
+        (None, _) if busicom.is_some() => run_busicom(&mut cpu, busicom.as_ref().unwrap()),
+        (None, Some(display)) => {
+            let mut next = 0;
+            cpu.run_watching(|cpu| {
+                if cpu.cycles() >= next {
+                    next = cpu.cycles() + REFRESH_CYCLES;
+                    show(display.screen(cpu), &mut shown);
+                }
+                true
+            })
+        },
+        (None, None) => cpu.run(),
+    };
+    if let (Some(tracer), Some(file_name)) = (cpu.tracer_mut(), trace_file) {
+        let flushed = tracer.flush().err();
+        if let Some(e) = tracer.error().or(flushed.as_ref()) {
+            eprintln!("{}: trace stopped: {}", file_name, e);
+        }
+    }
+    cpu.set_tracer(None);
+    if let Some(ref mut display) = display {
+        show(display.screen(&cpu), &mut shown);
+    }
+    print!("{}", cpu);
+    println!("stopped after {} cycles ({:?}): {}", cpu.cycles(), cpu.elapsed(), reason);
+
+    if let Some(file_name) = save_state {
+        let mut file = fs::File::create(&file_name).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file_name, e);
+            process::exit(1);
+        });
+        cpu.save_state(&mut file).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file_name, e);
+            process::exit(1);
+        });
+    }
+
+}
+
+fn usage() -> ! {
+    eprintln!("usage: box [--4040] [--rom-bank1 FILE] [--interrupt WAVEFORM]");
+    eprintln!("           [--test-waveform WAVEFORM]");
+    eprintln!("           [--cycles N] [--load-state FILE] [--save-state FILE]");
+    eprintln!("           [--machine FILE] [--display REGISTERS[,status]|PORT] [--busicom]");
+    eprintln!("           [--segments DIGITS:LOW,HIGH,SELECT[:onehot]]");
+    eprintln!("           [--break ADDRESS[#HITS][:CONDITION]] [--watch LOCATION[:r|w|rw]]");
+    eprintln!("           [--trace FILE] [--trace-format text|json] [--trace-pc LOW-HIGH]");
+    eprintln!("           [--trace-class jump,register,io,accumulator,control] ROM");
+    process::exit(2);
+}
+
+fn parse_arg<T: std::str::FromStr>(arg: Option<String>) -> T {
+    arg.and_then(|x| x.parse().ok()).unwrap_or_else(|| usage())
+}
+
+// Hex address with an optional hit count and condition, e.g. 1f0, 1f0#3 or
+// "1f0:acc == 0".
+fn parse_breakpoint(arg: Option<String>) -> breakpoint::Breakpoint {
+    let arg = arg.unwrap_or_else(|| usage());
+    let mut parts = arg.splitn(2, ':');
+    let mut address = parts.next().unwrap_or("").splitn(2, '#');
+    let breakpoint = breakpoint::Breakpoint::new(
+        u16::from_str_radix(address.next().unwrap_or(""), 16).unwrap_or_else(|_| usage()));
+    let breakpoint = match address.next() {
+        Some(hits) => breakpoint.with_hit_count(hits.parse().unwrap_or_else(|_| usage())),
+        None => breakpoint,
+    };
+
+    match parts.next() {
+        Some(condition) => breakpoint.with_condition(condition.parse().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        })),
+        None => breakpoint,
+    }
+}
+
+// Any location parse_location takes, and r, w or rw for reads, writes or
+// both, e.g. ram0.r1.c2:w or rom3.io. Both if left out.
+fn parse_watchpoint(arg: Option<String>) -> breakpoint::Watchpoint {
+    let arg = arg.unwrap_or_else(|| usage());
+    let mut parts = arg.splitn(2, ':');
+    let location = parse_location(parts.next().unwrap_or(""));
+    let access = match parts.next() {
+        Some("r") => breakpoint::Access::Read,
+        Some("w") => breakpoint::Access::Write,
+        Some("rw") | None => breakpoint::Access::ReadWrite,
+        Some(_) => usage(),
+    };
+    breakpoint::Watchpoint::new(location, access)
+}
+
+// Cycle stamped pin levels, e.g. "0:0 5000:1 5100:0".
+fn parse_waveform(arg: Option<String>) -> signal::Waveform {
+    arg.unwrap_or_else(|| usage()).parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    })
+}
+
+// Hex address range, e.g. 100-1ff.
+fn parse_range(arg: Option<String>) -> (u16, u16) {
+    let arg = arg.unwrap_or_else(|| usage());
+    let mut parts = arg.splitn(2, '-').map(|x| u16::from_str_radix(x, 16));
+    match (parts.next(), parts.next()) {
+        (Some(Ok(low)), Some(Ok(high))) => (low, high),
+        _ => usage(),
+    }
+}
+
+// Keys from stdin a line at a time, printed lines to stdout and the lamps
+// to stderr. Stops a little after stdin closes and the last key is pressed.
+fn run_busicom(cpu: &mut cpu::CPU, busicom: &busicom::Busicom) -> cpu::StopReason {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut closed = false;
+    let mut idle_since = None;
+    let mut lamps = busicom.lamps();
+    cpu.run_watching(|cpu| {
+        loop {
+            match receiver.try_recv() {
+                Ok(line) => if let Err(e) = busicom.keyboard.borrow_mut().type_line(&line) {
+                    eprintln!("{}", e);
+                },
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    closed = true;
+                    break;
+                },
+            }
+        }
+
+        for line in busicom.printer.borrow_mut().take_lines() {
+            println!("{}{}", line.text, if line.red { "  (red)" } else { "" });
+        }
+
+        if busicom.lamps() != lamps {
+            lamps = busicom.lamps();
+            eprintln!("lamps: memory {} overflow {} minus {}",
+                      lamps.memory as u8, lamps.overflow as u8, lamps.minus as u8);
+        }
+
+        busicom.keyboard.borrow_mut().update(cpu.cycles());
+        if closed && busicom.keyboard.borrow().pending() == 0 {
+            let since = *idle_since.get_or_insert(cpu.cycles());
+            return cpu.cycles() < since + BUSICOM_IDLE_CYCLES;
+        }
+        true
+    })
+}
+
+// Redraw the display over the last one if it has changed.
+fn show(screen: display::Screen, shown: &mut Option<display::Screen>) {
+    if shown.as_ref() == Some(&screen) {
+        return;
+    }
+    if let Some(ref old) = *shown {
+        print!("\x1b[{}A", old.render_height());
+    }
+    print!("{}", screen.render());
+    std::io::stdout().flush().unwrap();
+    *shown = Some(screen);
+}
+
+// RAM registers to show as rows, e.g. ram0.r0,ram0.r1,status, or a single
+// ROM or RAM output port to stream characters to, e.g. rom3.io or
+// bank1.ram2.out.
+fn parse_display(arg: Option<String>) -> DisplaySpec {
+    let arg = arg.unwrap_or_else(|| usage());
+    let mut rows = Vec::new();
+    let mut status = false;
+
+    if !arg.contains(',') && (arg.ends_with(".io") || arg.ends_with(".out")) {
+        return DisplaySpec::Port(parse_port(&arg));
+    }
+
+    for item in arg.split(',') {
+        let (bank, rest) = split_bank(item);
+        match &rest.split('.').collect::<Vec<_>>()[..] {
+            ["status"] => status = true,
+            [chip, register] => rows.push(display::Row {
+                bank: in_range(bank, hardware::RAM_BANKS),
+                chip: parse_number(chip, "ram", hardware::RAM_CHIPS),
+                register: parse_number(register, "r", ram::NUM_OF_REGISTERS),
+            }),
+            _ => usage(),
+        }
+    }
+    if rows.is_empty() {
+        usage();
+    }
+    DisplaySpec::Ram(rows, status)
+}
+
+// Digit count then the ports for segments a-d, segments e-g and dp, and
+// digit select, e.g. 6:rom4.io,rom5.io,ram1.out. Add :onehot if the digit
+// select is a bit per digit rather than a number.
+fn parse_segments(arg: Option<String>) -> (usize, [hardware::Location; 3], segment::Select) {
+    let arg = arg.unwrap_or_else(|| usage());
+    let parts: Vec<&str> = arg.split(':').collect();
+    let select = match parts.get(2) {
+        None => segment::Select::Binary,
+        Some(&"onehot") => segment::Select::OneHot,
+        Some(_) => usage(),
+    };
+    let digits = parts[0].parse().unwrap_or_else(|_| usage());
+    if digits == 0 || (select == segment::Select::OneHot && digits > segment::MAX_ONE_HOT_DIGITS) {
+        usage();
+    }
+    let ports: Vec<_> = parts.get(1).unwrap_or_else(|| usage()).split(',').map(parse_port).collect();
+    match ports[..] {
+        [low, high, digit] => (digits, [low, high, digit], select),
+        _ => usage(),
+    }
+}
+
+// A ROM IO port or a RAM output port, e.g. rom3.io or bank1.ram2.out.
+fn parse_port(arg: &str) -> hardware::Location {
+    match parse_location(arg) {
+        port @ hardware::Location::RomPort { .. } | port @ hardware::Location::RamOutput { .. } => port,
+        _ => usage(),
+    }
+}
+
+// A port as for parse_port, or a RAM char or status char written the way
+// Location displays them, e.g. ram0.r1.cf or bank2.ram1.r3.s0.
+fn parse_location(arg: &str) -> hardware::Location {
+    let (bank, rest) = split_bank(arg);
+    match &rest.split('.').collect::<Vec<_>>()[..] {
+        [chip, register, index] if index.starts_with('c') => hardware::Location::RamChar {
+            bank: in_range(bank, hardware::RAM_BANKS),
+            chip: parse_number(chip, "ram", hardware::RAM_CHIPS),
+            register: parse_number(register, "r", ram::NUM_OF_REGISTERS),
+            character: parse_number(index, "c", ram::NUM_OF_CHARACTERS),
+        },
+        [chip, register, index] => hardware::Location::RamStatus {
+            bank: in_range(bank, hardware::RAM_BANKS),
+            chip: parse_number(chip, "ram", hardware::RAM_CHIPS),
+            register: parse_number(register, "r", ram::NUM_OF_REGISTERS),
+            status: parse_number(index, "s", ram::NUM_OF_STATUS_CHARACTERS),
+        },
+        [chip, "io"] => hardware::Location::RomPort {
+            bank: in_range(bank, hardware::ROM_BANKS),
+            chip: parse_number(chip, "rom", hardware::ROM_CHIPS),
+        },
+        [chip, "out"] => hardware::Location::RamOutput {
+            bank: in_range(bank, hardware::RAM_BANKS),
+            chip: parse_number(chip, "ram", hardware::RAM_CHIPS),
+        },
+        _ => usage(),
+    }
+}
+
+fn attach(hardware: &mut hardware::Hardware, port: hardware::Location, peripheral: Box<dyn Peripheral>) {
+    match port {
+        hardware::Location::RomPort { bank, chip } => hardware.attach_rom_peripheral(bank, chip, peripheral),
+        hardware::Location::RamOutput { bank, chip } => hardware.attach_ram_peripheral(bank, chip, peripheral),
+        _ => unreachable!(),
+    }
+}
+
+// Optional bankN. prefix.
+fn split_bank(arg: &str) -> (u8, &str) {
+    match arg.strip_prefix("bank") {
+        Some(rest) => {
+            let mut parts = rest.splitn(2, '.');
+            let bank = parse_arg(parts.next().map(|x| x.to_string()));
+            (bank, parts.next().unwrap_or_else(|| usage()))
+        },
+        None => (0, arg),
+    }
+}
+
+// Hex number after `prefix` that is less than `limit`, e.g. rom3.
+fn parse_number(arg: &str, prefix: &str, limit: usize) -> u8 {
+    let n = arg.strip_prefix(prefix)
+        .and_then(|x| u8::from_str_radix(x, 16).ok())
+        .unwrap_or_else(|| usage());
+    in_range(n, limit)
+}
 
+fn in_range(n: u8, limit: usize) -> u8 {
+    if n as usize >= limit {
+        usage();
+    }
+    n
 }
 
 fn read_rom(file_name: &str) -> Vec<u8>{
-    let mut file = fs::File::open(file_name).unwrap();
     let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
+    let result = fs::File::open(file_name).and_then(|mut file| file.read_to_end(&mut buffer));
+    if let Err(e) = result {
+        eprintln!("{}: {}", file_name, e);
+        process::exit(1);
+    }
     buffer
 }
@@ -0,0 +1,70 @@
+// Anything hung off a 4001 IO port or a 4002 output port. Keyboards,
+// printers, displays and test fixtures all plug in here.
+//
+// A peripheral on a ROM port is given the levels on the chip's output pins
+// after every WRR, and asked for the levels on its input pins on every RDR.
+// The chip's mask options still decide what the CPU sees. RAM output ports
+// can only be written so read is never called for them.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+pub trait Peripheral: fmt::Debug {
+    // The port was written at machine cycle `cycle`.
+    fn write(&mut self, value: u8, cycle: u64);
+
+    // Levels driven onto the port's input pins at `cycle`. None leaves them
+    // floating.
+    fn read(&mut self, _cycle: u64) -> Option<u8> {
+        None
+    }
+}
+
+// So the host can keep hold of a peripheral after attaching it, to look at
+// what a display is showing say.
+impl<P: Peripheral> Peripheral for Rc<RefCell<P>> {
+    fn write(&mut self, value: u8, cycle: u64) {
+        self.borrow_mut().write(value, cycle)
+    }
+
+    fn read(&mut self, cycle: u64) -> Option<u8> {
+        self.borrow_mut().read(cycle)
+    }
+}
+
+// Several peripherals on one port, like two shift registers sharing a data
+// bit with a clock bit each. Every write goes to all of them. Reads are
+// wire-ORed, a pin is high if anything drives it high.
+#[derive(Debug, Default)]
+pub struct Group {
+    peripherals: Vec<Box<dyn Peripheral>>,
+}
+
+impl Group {
+    pub fn new() -> Group {
+        Group::default()
+    }
+
+    // Builder style.
+    pub fn with(mut self, peripheral: Box<dyn Peripheral>) -> Group {
+        self.peripherals.push(peripheral);
+        self
+    }
+
+    pub fn add(&mut self, peripheral: Box<dyn Peripheral>) {
+        self.peripherals.push(peripheral);
+    }
+}
+
+impl Peripheral for Group {
+    fn write(&mut self, value: u8, cycle: u64) {
+        for peripheral in &mut self.peripherals {
+            peripheral.write(value, cycle);
+        }
+    }
+
+    fn read(&mut self, cycle: u64) -> Option<u8> {
+        self.peripherals.iter_mut().filter_map(|p| p.read(cycle)).fold(None, |a, x| Some(a.unwrap_or(0) | x))
+    }
+}
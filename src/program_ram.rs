@@ -0,0 +1,151 @@
+// Writable program memory, as on Intellec style development systems. The
+// RAM sits behind a 4008/4009 pair (or a 4289) in place of some of the
+// 4001s in ROM bank 0, and instruction fetches from those pages come from
+// the RAM instead.
+//
+// WPM writes the accumulator into the word addressed by the last SRC (low 8
+// bits) and by the latch of a ROM port (high 4 bits, written with WRR). A
+// word is written a nibble at a time, high nibble first. A flip-flop in the
+// 4008 picks the nibble and flips on every WPM.
+//
+// Bit 0 of a second ROM port enables writing. With it low WPM copies the
+// nibble into a latch instead, and RDR from the address port reads the
+// latch, which is how a 4004 reads program memory back. The 4040 has RPM to
+// read straight into the accumulator.
+
+use std::io::{Read, Write};
+use savestate;
+use savestate::SaveStateError;
+
+pub const DEFAULT_ADDRESS_PORT: u8 = 0xe;
+pub const DEFAULT_CONTROL_PORT: u8 = 0xf;
+
+const PAGE_SIZE: usize = 256;
+
+#[derive(Clone)]
+pub struct ProgramRam {
+    first_page: u8,
+    pages: u8,
+    address_port: u8,
+    control_port: u8,
+    words: Vec<u8>,
+    low_half: bool, // the flip-flop, true when the low nibble is next
+    read_back: u8,  // nibble copied by WPM with writing disabled
+}
+
+impl std::fmt::Debug for ProgramRam {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "-- program ram {:03x}-{:03x} --", self.start(), self.end() - 1)
+    }
+}
+
+impl ProgramRam {
+    // `pages` 256 word pages from `first_page`, in place of those ROM chips.
+    pub fn new(first_page: u8, pages: u8) -> ProgramRam {
+        assert!(pages > 0 && first_page as usize + pages as usize <= 16);
+        ProgramRam {
+            first_page,
+            pages,
+            address_port: DEFAULT_ADDRESS_PORT,
+            control_port: DEFAULT_CONTROL_PORT,
+            words: vec![0; pages as usize * PAGE_SIZE],
+            low_half: false,
+            read_back: 0,
+        }
+    }
+
+    // Builder style. ROM ports for the high address nibble and write enable.
+    pub fn with_ports(mut self, address_port: u8, control_port: u8) -> ProgramRam {
+        assert!(address_port < 16 && control_port < 16);
+        self.address_port = address_port;
+        self.control_port = control_port;
+        self
+    }
+
+    pub fn start(&self) -> u16 {
+        self.first_page as u16 * PAGE_SIZE as u16
+    }
+
+    pub fn end(&self) -> u16 {
+        self.start() + self.words.len() as u16
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        address >= self.start() && address < self.end()
+    }
+
+    pub fn address_port(&self) -> u8 {
+        self.address_port
+    }
+
+    pub fn control_port(&self) -> u8 {
+        self.control_port
+    }
+
+    pub fn read_word(&self, address: u16) -> u8 {
+        self.words[(address - self.start()) as usize]
+    }
+
+    pub fn write_word(&mut self, address: u16, value: u8) {
+        let index = (address - self.start()) as usize;
+        self.words[index] = value;
+    }
+
+    // Half 0 is the high nibble.
+    pub fn read_nibble(&self, address: u16, half: u8) -> u8 {
+        let word = self.read_word(address);
+        if half == 0 { word >> 4 } else { word & 0b1111 }
+    }
+
+    pub fn write_nibble(&mut self, address: u16, half: u8, value: u8) {
+        let word = self.read_word(address);
+        let word = if half == 0 {
+            (value << 4) | (word & 0b1111)
+        } else {
+            (word & 0b1111_0000) | value
+        };
+        self.write_word(address, word);
+    }
+
+    // Which nibble the next access is to, 0 for high.
+    pub fn half(&self) -> u8 {
+        self.low_half as u8
+    }
+
+    pub fn set_half(&mut self, half: u8) {
+        self.low_half = half != 0;
+    }
+
+    pub fn read_back(&self) -> u8 {
+        self.read_back
+    }
+
+    pub fn set_read_back(&mut self, value: u8) {
+        self.read_back = value;
+    }
+
+    pub fn save_state(&self, w: &mut dyn Write) -> Result<(), SaveStateError> {
+        savestate::write_u8(w, self.first_page)?;
+        savestate::write_u8(w, self.pages)?;
+        savestate::write_bool(w, self.low_half)?;
+        savestate::write_u8(w, self.read_back)?;
+        savestate::write_bytes(w, &self.words)?;
+        Ok(())
+    }
+
+    // Contents only. The state has to be for RAM in the same place.
+    pub fn load_state(&self, r: &mut dyn Read) -> Result<ProgramRam, SaveStateError> {
+        let first_page = savestate::read_u8(r)?;
+        let pages = savestate::read_u8(r)?;
+        if first_page != self.first_page || pages != self.pages {
+            return Err(SaveStateError::Corrupt("program RAM"));
+        }
+        let mut ram = self.clone();
+        ram.low_half = savestate::read_bool(r, "program RAM flip-flop")?;
+        ram.read_back = savestate::read_bounded(r, 16, "program RAM read back")?;
+        let mut words = vec![0; ram.words.len()];
+        r.read_exact(&mut words)?;
+        ram.words = words;
+        Ok(ram)
+    }
+}
@@ -2,7 +2,13 @@
 // 320 bits arranged as 4 registers of 20 x 4 bit chars.
 // 20 chars are made up of 16 main and 4 status
 
-const NUM_OF_REGISTERS: usize = 4;
+use std::io::{Read, Write};
+use savestate;
+use savestate::SaveStateError;
+
+pub const NUM_OF_REGISTERS: usize = 4;
+pub const NUM_OF_CHARACTERS: usize = MAIN_MEM_SIZE;
+pub const NUM_OF_STATUS_CHARACTERS: usize = STATUS_MEM_SIZE;
 const MAIN_MEM_SIZE: usize = 16;
 const STATUS_MEM_SIZE: usize = 4;
 
@@ -12,10 +18,10 @@ struct Register {
     status: [u8; STATUS_MEM_SIZE], //  4 x 4bit status chars
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Ram {
     registers: [Register; NUM_OF_REGISTERS],
-    output: u8 //  1 x 4bit output port
+    output: u8 //  1 x 4bit output port, one per chip
 }
 
 impl Ram {
@@ -50,4 +56,35 @@ impl Ram {
     pub fn write_output(&mut self, value: u8) {
         self.output = value;
     }
+
+    pub fn read_output(&self) -> u8 {
+        self.output
+    }
+
+    // Registers in order, main chars then status chars, then the output
+    // port.
+    pub fn save_state(&self, w: &mut dyn Write) -> Result<(), SaveStateError> {
+        for r in &self.registers {
+            savestate::write_bytes(w, &r.main)?;
+            savestate::write_bytes(w, &r.status)?;
+        }
+        savestate::write_u8(w, self.output)?;
+        Ok(())
+    }
+
+    pub fn load_state(r: &mut dyn Read) -> Result<Ram, SaveStateError> {
+        let mut ram = Ram::new();
+        for register in &mut ram.registers {
+            savestate::read_nibbles(r, &mut register.main, "RAM main char")?;
+            savestate::read_nibbles(r, &mut register.status, "RAM status char")?;
+        }
+        ram.output = savestate::read_bounded(r, 16, "RAM output port")?;
+        Ok(ram)
+    }
+}
+
+impl Default for Ram {
+    fn default() -> Ram {
+        Ram::new()
+    }
 }
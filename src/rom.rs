@@ -2,20 +2,66 @@
 // 256 x 8bit words
 // 4 x IO pins
 
-// The IO is incomplete. In reality the IO pins can be configured as input or
-// output. In both cases they can be direct or inverted. Pull up/down resistors
-// can be used in both cases. You can set an IO pin that is an output to return
-// a particular level when read. This is all set at manufacture. See the MCS
-// manual for more the full story. I don't plan on using the IO pins for now
-// so am just modeling them as 4 bits. I'll work out the details later.
+// Each IO pin is set at manufacture (the metal mask) to be an input or an
+// output. In both cases it can be direct or inverted and can have a pull up
+// or pull down resistor. An output can also be set to return a fixed level
+// when read. See the MCS manual for the full story.
+//
+// Here an input pin reads whatever is driving it from outside, or its pull
+// resistor if nothing is. Floating with no resistor reads low. An output pin
+// reads back what was last written unless it has a fixed read level.
 
 use std;
+use std::io::{Read, Write};
+use fault::Fault;
+use savestate;
+use savestate::SaveStateError;
 
-const ROM_SIZE: usize = 256;
+pub const ROM_SIZE: usize = 256;
+pub const NUM_PINS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pull {
+    None,
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinConfig {
+    pub direction: Direction,
+    pub inverted: bool,
+    pub pull: Pull,
+    pub read_level: Option<bool>, // outputs only, what RDR sees
+}
+
+// Direct output that reads back what was written. Behaves like a plain
+// 4 bit latch, which is what the IO used to be modeled as.
+impl Default for PinConfig {
+    fn default() -> PinConfig {
+        PinConfig {
+            direction: Direction::Output,
+            inverted: false,
+            pull: Pull::None,
+            read_level: None,
+        }
+    }
+}
+
+pub type PortConfig = [PinConfig; NUM_PINS];
 
 pub struct Rom {
     words: [u8; ROM_SIZE],
-    in_out: u8
+    config: PortConfig,
+    latch: u8,  // last value written by WRR
+    inputs: u8, // levels on input pins driven from outside
+    driven: u8  // which input pins are being driven
 }
 
 impl std::fmt::Debug for Rom {
@@ -25,30 +71,112 @@ impl std::fmt::Debug for Rom {
 }
 
 impl Rom {
-    pub fn new(rom: Vec<u8>) -> Rom {
-        assert!(rom.len() <= ROM_SIZE);
+    pub fn new(rom: Vec<u8>) -> Result<Rom, Fault> {
+        if rom.len() > ROM_SIZE {
+            return Err(Fault::BadImageSize { size: rom.len(), max: ROM_SIZE });
+        }
         let mut r = Rom {
             words: [0; ROM_SIZE],
-            in_out: 0
+            config: [PinConfig::default(); NUM_PINS],
+            latch: 0,
+            inputs: 0,
+            driven: 0
         };
 
-        for x in 0..rom.len() {
-            r.words[x] = rom[x];
-        }
+        r.words[..rom.len()].copy_from_slice(&rom);
 
-        r
+        Ok(r)
     }
 
     pub fn read_word(&self, address: u8) -> u8 {
         self.words[address as usize]
     }
 
+    pub fn config(&self) -> PortConfig {
+        self.config
+    }
+
+    pub fn configure(&mut self, config: PortConfig) {
+        self.config = config;
+    }
+
+    // What RDR sees.
     pub fn read_port(&self) -> u8 {
-        self.in_out
+        let mut value = 0;
+        for (pin, config) in self.config.iter().enumerate() {
+            let bit = 1 << pin;
+            let level = match config.direction {
+                Direction::Output => match config.read_level {
+                    Some(level) => level,
+                    None => return_bit(self.latch, bit),
+                },
+                Direction::Input => {
+                    let level = if self.driven & bit != 0 {
+                        return_bit(self.inputs, bit)
+                    } else {
+                        config.pull == Pull::Up
+                    };
+                    level != config.inverted
+                },
+            };
+            if level {
+                value |= bit;
+            }
+        }
+        value
     }
 
     pub fn write_port(&mut self, value: u8) {
-        self.in_out = value;
+        self.latch = value;
+    }
+
+    // Last value written, whatever the pins are configured as.
+    pub fn read_latch(&self) -> u8 {
+        self.latch
+    }
+
+    // Levels on the output pins as the outside world sees them. Input pins
+    // read as 0.
+    pub fn outputs(&self) -> u8 {
+        let mut value = 0;
+        for (pin, config) in self.config.iter().enumerate() {
+            let bit = 1 << pin;
+            if config.direction == Direction::Output && return_bit(self.latch, bit) != config.inverted {
+                value |= bit;
+            }
+        }
+        value
+    }
+
+    // Drive the input pins selected by `mask` to `levels`. Unselected pins
+    // are left floating.
+    pub fn drive_inputs(&mut self, levels: u8, mask: u8) {
+        self.inputs = levels & mask;
+        self.driven = mask & 0b1111;
     }
 
+    pub fn save_state(&self, w: &mut dyn Write) -> Result<(), SaveStateError> {
+        savestate::write_bytes(w, &[self.latch, self.inputs, self.driven])?;
+        Ok(())
+    }
+
+    // Returns (latch, inputs, driven) for apply_state. Split so the hardware
+    // can check every chip before changing any.
+    pub fn read_state(r: &mut dyn Read) -> Result<(u8, u8, u8), SaveStateError> {
+        let mut state = [0; 3];
+        savestate::read_nibbles(r, &mut state, "ROM IO port")?;
+        Ok((state[0], state[1], state[2]))
+    }
+
+    pub fn apply_state(&mut self, state: (u8, u8, u8)) {
+        let (latch, inputs, driven) = state;
+        self.latch = latch;
+        self.inputs = inputs;
+        self.driven = driven;
+    }
+
+}
+
+fn return_bit(value: u8, bit: u8) -> bool {
+    value & bit != 0
 }
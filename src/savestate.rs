@@ -0,0 +1,124 @@
+// Save state file format
+// A snapshot of everything that changes while a program runs. ROM contents
+// aren't included, load the same image before restoring.
+//
+//   "BOXS"   magic
+//   u16      format version
+//   ...      CPU registers then hardware, see CPU::save_state
+//
+// Multi-byte values are little endian. Bump VERSION whenever the layout
+// changes.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+pub const MAGIC: &[u8; 4] = b"BOXS";
+pub const VERSION: u16 = 5;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Corrupt(&'static str), // field that failed validation
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveStateError::Io(ref e) => write!(f, "{}", e),
+            SaveStateError::BadMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(v) =>
+                write!(f, "save state version {} not supported (expected {})", v, VERSION),
+            SaveStateError::Corrupt(field) => write!(f, "save state corrupt: bad {}", field),
+        }
+    }
+}
+
+impl error::Error for SaveStateError {}
+
+impl From<io::Error> for SaveStateError {
+    fn from(e: io::Error) -> SaveStateError {
+        SaveStateError::Io(e)
+    }
+}
+
+pub fn write_header(w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    write_u16(w, VERSION)
+}
+
+pub fn read_header(r: &mut dyn Read) -> Result<(), SaveStateError> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SaveStateError::BadMagic);
+    }
+    match read_u16(r)? {
+        VERSION => Ok(()),
+        v => Err(SaveStateError::UnsupportedVersion(v)),
+    }
+}
+
+pub fn write_u8(w: &mut dyn Write, value: u8) -> io::Result<()> {
+    w.write_all(&[value])
+}
+
+pub fn write_bool(w: &mut dyn Write, value: bool) -> io::Result<()> {
+    write_u8(w, value as u8)
+}
+
+pub fn write_u16(w: &mut dyn Write, value: u16) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_u64(w: &mut dyn Write, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_bytes(w: &mut dyn Write, values: &[u8]) -> io::Result<()> {
+    w.write_all(values)
+}
+
+pub fn read_u8(r: &mut dyn Read) -> io::Result<u8> {
+    let mut b = [0; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+// A u8 that must be less than `limit`. Registers are 4 bit or narrower so
+// anything bigger means the file is damaged.
+pub fn read_bounded(r: &mut dyn Read, limit: u8, field: &'static str) -> Result<u8, SaveStateError> {
+    let value = read_u8(r)?;
+    if value >= limit {
+        return Err(SaveStateError::Corrupt(field));
+    }
+    Ok(value)
+}
+
+pub fn read_bool(r: &mut dyn Read, field: &'static str) -> Result<bool, SaveStateError> {
+    Ok(read_bounded(r, 2, field)? == 1)
+}
+
+pub fn read_u16(r: &mut dyn Read) -> io::Result<u16> {
+    let mut b = [0; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+
+pub fn read_u64(r: &mut dyn Read) -> io::Result<u64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+// Fill `values` with nibbles.
+pub fn read_nibbles(r: &mut dyn Read, values: &mut [u8], field: &'static str) -> Result<(), SaveStateError> {
+    r.read_exact(values)?;
+    if values.iter().any(|&x| x > 0b1111) {
+        return Err(SaveStateError::Corrupt(field));
+    }
+    Ok(())
+}
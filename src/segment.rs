@@ -0,0 +1,223 @@
+// Multiplexed seven segment LED display. Ports are only 4 bits so the
+// segments are split over two ports, a to d on one and e to g plus the
+// decimal point on another, and a third picks the digit being driven. The
+// program lights one digit at a time, fast enough that they all look lit.
+//
+// To see what a person would, the time each segment of each digit spends
+// lit is added up and looked at over a frame. A segment counts as lit if it
+// was on for at least half as long as the brightest one, which ignores the
+// glimmer while the program is changing over from one digit to the next.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use peripheral::Peripheral;
+
+pub const SEGMENTS: usize = 8; // a-g and the decimal point
+pub const DECIMAL_POINT: u8 = 0b1000_0000;
+pub const MAX_ONE_HOT_DIGITS: usize = 4; // a bit each in a 4 bit port
+
+// Segment patterns, bit 0 is a and bit 6 is g.
+const GLYPHS: &[(u8, char)] = &[
+    (0b011_1111, '0'), (0b000_0110, '1'), (0b101_1011, '2'), (0b100_1111, '3'),
+    (0b110_0110, '4'), (0b110_1101, '5'), (0b111_1101, '6'), (0b000_0111, '7'),
+    (0b111_1111, '8'), (0b110_1111, '9'), (0b111_0111, 'A'), (0b111_1100, 'b'),
+    (0b011_1001, 'C'), (0b101_1110, 'd'), (0b111_1001, 'E'), (0b111_0001, 'F'),
+    (0b100_0000, '-'), (0b000_1000, '_'), (0b111_0110, 'H'), (0b011_1000, 'L'),
+    (0b111_0011, 'P'), (0b011_1110, 'U'), (0b000_0000, ' '),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Select {
+    Binary, // the nibble is the digit number, anything past the end is none
+    OneHot, // bit n lights digit n, up to 4 digits
+}
+
+// Which part of the display a port drives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    SegmentsLow,  // a b c d
+    SegmentsHigh, // e f g dp
+    Digit,
+}
+
+#[derive(Debug, Clone)]
+pub struct SevenSegment {
+    select: Select,
+    segments: u8,
+    digit: u8,
+    lit: Vec<[u64; SEGMENTS]>, // cycles lit this frame, per digit
+    since: u64,                // cycle lit was last brought up to date
+    frame: Vec<u8>,            // what was seen last frame
+}
+
+impl SevenSegment {
+    pub fn new(digits: usize, select: Select) -> SevenSegment {
+        assert!(digits > 0 && (select == Select::Binary || digits <= MAX_ONE_HOT_DIGITS));
+        SevenSegment {
+            select,
+            segments: 0,
+            digit: 0,
+            lit: vec![[0; SEGMENTS]; digits],
+            since: 0,
+            frame: vec![0; digits],
+        }
+    }
+
+    pub fn digits(&self) -> usize {
+        self.lit.len()
+    }
+
+    // A peripheral to attach to the port that drives `role`.
+    pub fn port(display: &Rc<RefCell<SevenSegment>>, role: Role) -> Box<dyn Peripheral> {
+        Box::new(SegmentPort { display: display.clone(), role })
+    }
+
+    fn driven(&self, digit: usize) -> bool {
+        match self.select {
+            Select::Binary => self.digit as usize == digit,
+            Select::OneHot => self.digit & (1 << digit) != 0,
+        }
+    }
+
+    // Add the time since the last change to whatever was lit.
+    fn catch_up(&mut self, cycle: u64) {
+        let elapsed = cycle.saturating_sub(self.since);
+        for digit in 0..self.digits() {
+            if self.driven(digit) {
+                for segment in 0..SEGMENTS {
+                    if self.segments & (1 << segment) != 0 {
+                        self.lit[digit][segment] += elapsed;
+                    }
+                }
+            }
+        }
+        self.since = self.since.max(cycle);
+    }
+
+    fn write(&mut self, role: Role, value: u8, cycle: u64) {
+        self.catch_up(cycle);
+        match role {
+            Role::SegmentsLow => self.segments = (self.segments & 0xf0) | value,
+            Role::SegmentsHigh => self.segments = (self.segments & 0x0f) | (value << 4),
+            Role::Digit => self.digit = value,
+        }
+    }
+
+    // End the frame at `cycle` and work out what it looked like, segment
+    // patterns per digit with the decimal point in bit 7. Frames need to be
+    // longer than it takes the program to go round all the digits or some
+    // will look dark.
+    pub fn frame(&mut self, cycle: u64) -> &[u8] {
+        self.catch_up(cycle);
+        let brightest = self.lit.iter().flat_map(|d| d.iter()).cloned().max().unwrap_or(0);
+        for (digit, lit) in self.lit.iter().enumerate() {
+            self.frame[digit] = (0..SEGMENTS)
+                .filter(|&s| brightest > 0 && lit[s] * 2 >= brightest)
+                .fold(0, |pattern, s| pattern | (1 << s));
+        }
+        for lit in &mut self.lit {
+            *lit = [0; SEGMENTS];
+        }
+        &self.frame
+    }
+
+    // The last frame as text, digit 0 on the left. Patterns that aren't a
+    // character show as '?'.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for &pattern in &self.frame {
+            text.push(glyph(pattern & !DECIMAL_POINT));
+            if pattern & DECIMAL_POINT != 0 {
+                text.push('.');
+            }
+        }
+        text
+    }
+
+    // The last frame drawn in three lines of _ and |.
+    pub fn render(&self) -> Vec<String> {
+        let mut lines = vec![String::new(), String::new(), String::new()];
+        for &p in &self.frame {
+            let on = |segment: u8, c: char| if p & (1 << segment) != 0 { c } else { ' ' };
+            lines[0].push_str(&format!(" {}  ", on(0, '_')));
+            lines[1].push_str(&format!("{}{}{} ", on(5, '|'), on(6, '_'), on(1, '|')));
+            lines[2].push_str(&format!("{}{}{}{}", on(4, '|'), on(3, '_'), on(2, '|'), on(7, '.')));
+        }
+        lines
+    }
+}
+
+fn glyph(pattern: u8) -> char {
+    GLYPHS.iter().find(|g| g.0 == pattern).map_or('?', |g| g.1)
+}
+
+#[derive(Debug)]
+struct SegmentPort {
+    display: Rc<RefCell<SevenSegment>>,
+    role: Role,
+}
+
+impl Peripheral for SegmentPort {
+    fn write(&mut self, value: u8, cycle: u64) {
+        self.display.borrow_mut().write(self.role, value & 0b1111, cycle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Show `patterns` one digit at a time for `cycles` each, then end the
+    // frame.
+    fn scan(display: &Rc<RefCell<SevenSegment>>, patterns: &[u8], cycles: u64) {
+        let mut low = SevenSegment::port(display, Role::SegmentsLow);
+        let mut high = SevenSegment::port(display, Role::SegmentsHigh);
+        let mut digit = SevenSegment::port(display, Role::Digit);
+        let one_hot = display.borrow().select == Select::OneHot;
+
+        let mut cycle = 0;
+        for (n, &pattern) in patterns.iter().enumerate() {
+            low.write(pattern, cycle);
+            high.write(pattern >> 4, cycle);
+            digit.write(if one_hot { 1 << n } else { n as u8 }, cycle);
+            cycle += cycles;
+        }
+        display.borrow_mut().frame(cycle);
+    }
+
+    #[test]
+    fn text() {
+        let display = Rc::new(RefCell::new(SevenSegment::new(4, Select::Binary)));
+        scan(&display, &[0b000_0110, 0b111_1001 | DECIMAL_POINT, 0b111_0110, 0b101_0101], 100);
+        assert_eq!(display.borrow().text(), "1E.H?");
+    }
+
+    #[test]
+    fn one_hot_select() {
+        let display = Rc::new(RefCell::new(SevenSegment::new(2, Select::OneHot)));
+        scan(&display, &[0b101_1011, 0b100_1111], 100);
+        assert_eq!(display.borrow().text(), "23");
+        assert_eq!(display.borrow().render(), vec![" _   _  ", " _|  _| ", "|_   _| "]);
+    }
+
+    #[test]
+    fn glimmer_is_ignored() {
+        // the next digit's segments are briefly shown on the last digit
+        let display = Rc::new(RefCell::new(SevenSegment::new(2, Select::Binary)));
+        let mut low = SevenSegment::port(&display, Role::SegmentsLow);
+        let mut digit = SevenSegment::port(&display, Role::Digit);
+        low.write(0b0110, 0);
+        digit.write(0, 0);
+        low.write(0b1000, 100);
+        digit.write(1, 110);
+        display.borrow_mut().frame(210);
+        assert_eq!(display.borrow().text(), "1_");
+    }
+
+    #[test]
+    fn dark_frame() {
+        let display = Rc::new(RefCell::new(SevenSegment::new(3, Select::Binary)));
+        display.borrow_mut().frame(1000);
+        assert_eq!(display.borrow().text(), "   ");
+    }
+}
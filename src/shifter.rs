@@ -0,0 +1,118 @@
+// 4003 10 bit shift register. Serial in, parallel out, with a serial out
+// so two or more can be chained into one longer register. Real systems use
+// them to get more outputs than the ROM and RAM ports give, keyboard column
+// scanning and printer hammer selection on the Busicom for example.
+//
+// Wired to a port as a peripheral. One port bit is the clock, another the
+// data in. Data is shifted in on the rising edge of the clock. Q0 is the
+// stage data goes into, so after ten clocks the first bit sent is on Q9.
+// The outputs can optionally be gated by an enable bit.
+
+use std::fmt;
+use peripheral::Peripheral;
+
+pub const STAGES: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShiftRegister {
+    chips: usize,
+    clock_bit: u8,
+    data_bit: u8,
+    enable_bit: Option<u8>,
+    stages: u64, // bit n is Qn, chip c's outputs are bits 10c to 10c+9
+    clock: bool,
+    enabled: bool,
+}
+
+impl ShiftRegister {
+    pub fn new(clock_bit: u8, data_bit: u8) -> ShiftRegister {
+        assert!(clock_bit < 4 && data_bit < 4);
+        ShiftRegister {
+            chips: 1,
+            clock_bit,
+            data_bit,
+            enable_bit: None,
+            stages: 0,
+            clock: false,
+            enabled: true,
+        }
+    }
+
+    // Builder style. `chips` 4003s with each serial out wired to the next
+    // one's data in.
+    pub fn chained(mut self, chips: usize) -> ShiftRegister {
+        assert!(chips >= 1 && chips * STAGES <= 64);
+        self.chips = chips;
+        self
+    }
+
+    // Builder style. Outputs read as 0 while `bit` is low.
+    pub fn with_enable(mut self, bit: u8) -> ShiftRegister {
+        assert!(bit < 4);
+        self.enable_bit = Some(bit);
+        self.enabled = false;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.chips * STAGES
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Parallel outputs, bit n is Qn.
+    pub fn outputs(&self) -> u64 {
+        if self.enabled { self.stages } else { 0 }
+    }
+
+    pub fn output(&self, stage: usize) -> bool {
+        self.outputs() & (1 << stage) != 0
+    }
+
+    // What comes out of the last chip's serial out.
+    pub fn serial_out(&self) -> bool {
+        self.stages & (1 << (self.len() - 1)) != 0
+    }
+
+    pub fn clear(&mut self) {
+        self.stages = 0;
+    }
+
+    fn shift(&mut self, data: bool) {
+        let mask = (1 << self.len()) - 1;
+        self.stages = ((self.stages << 1) | data as u64) & mask;
+    }
+}
+
+impl Peripheral for ShiftRegister {
+    fn write(&mut self, value: u8, _cycle: u64) {
+        let clock = value & (1 << self.clock_bit) != 0;
+        if clock && !self.clock {
+            self.shift(value & (1 << self.data_bit) != 0);
+        }
+        self.clock = clock;
+        if let Some(bit) = self.enable_bit {
+            self.enabled = value & (1 << bit) != 0;
+        }
+    }
+}
+
+// Q0 first, a space between chips.
+impl fmt::Display for ShiftRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "4003")?;
+        for chip in 0..self.chips {
+            write!(f, " ")?;
+            for stage in 0..STAGES {
+                let bit = self.stages & (1 << (chip * STAGES + stage)) != 0;
+                write!(f, "{}", bit as u8)?;
+            }
+        }
+        if !self.enabled {
+            write!(f, " (disabled)")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+// The TEST input pin. JCN can branch on it so it is how 4004 programs wait
+// for the outside world, printer drum sector pulses on the Busicom for
+// example. The pin can be held at a fixed level, driven from a scripted
+// waveform or driven by anything implementing TestSignal.
+
+use std::fmt;
+use std::str::FromStr;
+
+// Level of the pin at machine cycle `cycle`. true is high.
+pub trait TestSignal: fmt::Debug {
+    fn level(&mut self, cycle: u64) -> bool;
+}
+
+// A list of cycle stamped levels. The pin holds each level from its cycle
+// until the next one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Waveform {
+    initial: bool,
+    edges: Vec<(u64, bool)> // sorted by cycle
+}
+
+impl Waveform {
+    pub fn new(initial: bool) -> Waveform {
+        Waveform {
+            initial,
+            edges: Vec::new()
+        }
+    }
+
+    // Builder style. Edges can be added in any order.
+    pub fn at(mut self, cycle: u64, level: bool) -> Waveform {
+        let index = match self.edges.binary_search_by_key(&cycle, |&(c, _)| c) {
+            Ok(x) => {
+                self.edges[x].1 = level;
+                return self;
+            },
+            Err(x) => x,
+        };
+        self.edges.insert(index, (cycle, level));
+        self
+    }
+
+    pub fn level_at(&self, cycle: u64) -> bool {
+        match self.edges.binary_search_by_key(&cycle, |&(c, _)| c) {
+            Ok(x) => self.edges[x].1,
+            Err(0) => self.initial,
+            Err(x) => self.edges[x - 1].1,
+        }
+    }
+}
+
+impl TestSignal for Waveform {
+    fn level(&mut self, cycle: u64) -> bool {
+        self.level_at(cycle)
+    }
+}
+
+// Whitespace or comma separated `cycle:level` pairs, e.g. "0:1 200:0 210:1".
+// Levels are 0 or 1. The pin is low until the first edge.
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Waveform, String> {
+        let mut waveform = Waveform::new(false);
+
+        for edge in s.split(|c: char| c == ',' || c.is_whitespace()).filter(|x| !x.is_empty()) {
+            let mut parts = edge.splitn(2, ':');
+            let cycle = parts.next().unwrap_or("");
+            let level = parts.next().ok_or_else(|| format!("missing level in '{}'", edge))?;
+
+            let cycle = cycle.parse::<u64>().map_err(|_| format!("bad cycle in '{}'", edge))?;
+            let level = match level {
+                "0" => false,
+                "1" => true,
+                _   => return Err(format!("bad level in '{}'", edge)),
+            };
+            waveform = waveform.at(cycle, level);
+        }
+
+        Ok(waveform)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        let mut waveform = Waveform::new(true).at(20, true).at(10, false).at(20, false);
+        assert!(waveform.level(0));
+        assert!(!waveform.level(10));
+        assert!(!waveform.level(15));
+        assert!(!waveform.level(25));
+    }
+
+    #[test]
+    fn parse() {
+        let waveform: Waveform = "0:1 200:0,210:1".parse().unwrap();
+        assert_eq!(waveform, Waveform::new(false).at(0, true).at(200, false).at(210, true));
+        assert!(waveform.level_at(199));
+        assert!(!waveform.level_at(205));
+        assert_eq!("".parse(), Ok(Waveform::new(false)));
+
+        assert_eq!("5".parse::<Waveform>(), Err("missing level in '5'".to_string()));
+        assert_eq!("x:1".parse::<Waveform>(), Err("bad cycle in 'x:1'".to_string()));
+        assert_eq!("5:2".parse::<Waveform>(), Err("bad level in '5:2'".to_string()));
+    }
+}
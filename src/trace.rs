@@ -0,0 +1,302 @@
+// Per-instruction execution trace
+// One record per instruction, either as a line of text or as JSON Lines.
+// Each record holds the cycle count the instruction started on, where it
+// was and what it was, the accumulator and carry either side of it, any
+// index registers it changed and any RAM or port writes it made.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use cpu::MemoryWrite;
+use hardware::Location;
+use instruction::Class;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    JsonLines,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text"           => Ok(Format::Text),
+            "json" | "jsonl" => Ok(Format::JsonLines),
+            _                => Err(format!("unknown trace format '{}'", s)),
+        }
+    }
+}
+
+// Which instructions get traced. Empty/None means no restriction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub addresses: Option<(u16, u16)>, // inclusive
+    pub classes: Vec<Class>,
+}
+
+impl Filter {
+    pub fn accepts(&self, address: u16, class: Class) -> bool {
+        let in_range = match self.addresses {
+            Some((low, high)) => address >= low && address <= high,
+            None => true,
+        };
+        in_range && (self.classes.is_empty() || self.classes.contains(&class))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub cycle: u64,
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: String,
+    pub class: Class,
+    pub accumulator: (u8, u8),        // before, after
+    pub carry: (u8, u8),              // before, after
+    pub registers: Vec<(u8, u8, u8)>, // register, before, after
+    pub writes: Vec<MemoryWrite>,
+}
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: Format,
+    filter: Filter,
+    error: Option<io::Error>, // the write that failed. Nothing is written after it.
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: Format) -> Tracer {
+        Tracer {
+            out,
+            format,
+            filter: Filter::default(),
+            error: None,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P, format: Format) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file)), format))
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    pub fn accepts(&self, address: u16, class: Class) -> bool {
+        self.filter.accepts(address, class)
+    }
+
+    // Write errors are kept for error() rather than returned, as records
+    // are made from deep inside the CPU.
+    pub fn record(&mut self, record: &Record) {
+        if self.error.is_some() || !self.accepts(record.address, record.class) {
+            return;
+        }
+        let result = match self.format {
+            Format::Text      => write_text(&mut self.out, record),
+            Format::JsonLines => write_json(&mut self.out, record),
+        };
+        self.error = result.err();
+    }
+
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+impl ::std::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Tracer({:?}, {:?})", self.format, self.filter)
+    }
+}
+
+//      cycle  pc   bytes  instruction    acc  carry  changes
+//         12  002  A0     LD  R0         2>a  0>0    r0 a>b  ram0.r0.c2 0>2
+fn write_text(out: &mut dyn Write, r: &Record) -> io::Result<()> {
+    let bytes = r.bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+    write!(out, "{:10}  {:03X}  {:<5}  {:<14} {:x}>{:x}  {}>{}",
+           r.cycle, r.address, bytes, r.mnemonic,
+           r.accumulator.0, r.accumulator.1, r.carry.0, r.carry.1)?;
+    for &(register, before, after) in &r.registers {
+        write!(out, "  r{} {:x}>{:x}", register, before, after)?;
+    }
+    for w in &r.writes {
+        write!(out, "  {} {:x}>{:x}", w.location, w.old, w.new)?;
+    }
+    writeln!(out)
+}
+
+fn write_json(out: &mut dyn Write, r: &Record) -> io::Result<()> {
+    let bytes = r.bytes.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(",");
+    write!(out, "{{\"cycle\":{},\"pc\":{},\"bytes\":[{}],\"mnemonic\":\"{}\",\"class\":\"{}\",\
+                 \"acc\":[{},{}],\"carry\":[{},{}],\"registers\":[",
+           r.cycle, r.address, bytes, r.mnemonic, class_name(r.class),
+           r.accumulator.0, r.accumulator.1, r.carry.0, r.carry.1)?;
+    for (i, &(register, before, after)) in r.registers.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        write!(out, "{}{{\"r\":{},\"old\":{},\"new\":{}}}", sep, register, before, after)?;
+    }
+    write!(out, "],\"writes\":[")?;
+    for (i, w) in r.writes.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        write!(out, "{}{{{},\"old\":{},\"new\":{}}}", sep, location_json(w.location), w.old, w.new)?;
+    }
+    writeln!(out, "]}}")
+}
+
+fn class_name(class: Class) -> &'static str {
+    match class {
+        Class::Jump        => "jump",
+        Class::Register    => "register",
+        Class::Io          => "io",
+        Class::Accumulator => "accumulator",
+        Class::Control     => "control",
+    }
+}
+
+fn location_json(location: Location) -> String {
+    match location {
+        Location::RamChar { bank, chip, register, character } =>
+            format!("\"kind\":\"ram_char\",\"bank\":{},\"chip\":{},\"register\":{},\"character\":{}",
+                    bank, chip, register, character),
+        Location::RamStatus { bank, chip, register, status } =>
+            format!("\"kind\":\"ram_status\",\"bank\":{},\"chip\":{},\"register\":{},\"status\":{}",
+                    bank, chip, register, status),
+        Location::RamOutput { bank, chip } =>
+            format!("\"kind\":\"ram_output\",\"bank\":{},\"chip\":{}", bank, chip),
+        Location::RomPort { bank, chip } =>
+            format!("\"kind\":\"rom_port\",\"bank\":{},\"chip\":{}", bank, chip),
+        Location::ProgramRam { address, half } =>
+            format!("\"kind\":\"program_ram\",\"address\":{},\"half\":{}", address, half),
+        Location::ProgramRamHalf =>
+            "\"kind\":\"program_ram_half\"".to_string(),
+        Location::ProgramRamReadBack =>
+            "\"kind\":\"program_ram_read_back\"".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use cpu::{CPU, StopReason};
+    use hardware::Hardware;
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record(address: u16, class: Class) -> Record {
+        Record {
+            cycle: 12,
+            address,
+            bytes: vec![0xa0],
+            mnemonic: "LD  R0".to_string(),
+            class,
+            accumulator: (2, 0xa),
+            carry: (0, 0),
+            registers: vec![(0, 0xa, 0xb)],
+            writes: vec![MemoryWrite {
+                location: Location::RamChar { bank: 0, chip: 0, register: 0, character: 2 },
+                old: 0,
+                new: 2,
+            }],
+        }
+    }
+
+    fn traced(format: Format, filter: Filter, records: &[Record]) -> String {
+        let out = Shared::default();
+        let mut tracer = Tracer::new(Box::new(out.clone()), format);
+        tracer.set_filter(filter);
+        for r in records {
+            tracer.record(r);
+        }
+        assert!(tracer.error().is_none());
+        let bytes = out.0.borrow().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(traced(Format::Text, Filter::default(), &[record(2, Class::Register)]),
+                   "        12  002  A0     LD  R0         2>a  0>0  r0 a>b  ram0.r0.c2 0>2\n");
+    }
+
+    #[test]
+    fn json_lines() {
+        assert_eq!(traced(Format::JsonLines, Filter::default(), &[record(2, Class::Register)]),
+                   "{\"cycle\":12,\"pc\":2,\"bytes\":[160],\"mnemonic\":\"LD  R0\",\"class\":\"register\",\
+                    \"acc\":[2,10],\"carry\":[0,0],\"registers\":[{\"r\":0,\"old\":10,\"new\":11}],\
+                    \"writes\":[{\"kind\":\"ram_char\",\"bank\":0,\"chip\":0,\"register\":0,\"character\":2,\
+                    \"old\":0,\"new\":2}]}\n");
+    }
+
+    #[test]
+    fn filters() {
+        let records = [record(1, Class::Register), record(2, Class::Io), record(3, Class::Register)];
+        let pcs = |filter: Filter| -> Vec<String> {
+            traced(Format::JsonLines, filter, &records).lines()
+                .map(|line| line.split(',').nth(1).unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(pcs(Filter::default()).len(), 3);
+        assert_eq!(pcs(Filter { addresses: Some((2, 3)), classes: Vec::new() }), ["\"pc\":2", "\"pc\":3"]);
+        assert_eq!(pcs(Filter { addresses: None, classes: vec![Class::Register] }), ["\"pc\":1", "\"pc\":3"]);
+        assert_eq!(pcs(Filter { addresses: Some((2, 3)), classes: vec![Class::Register] }), ["\"pc\":3"]);
+    }
+
+    #[test]
+    fn write_error() {
+        let mut tracer = Tracer::new(Box::new(Broken), Format::Text);
+        tracer.record(&record(2, Class::Register));
+        assert_eq!(tracer.error().unwrap().to_string(), "disk full");
+
+        // and it is still there after a run
+        let mut cpu = CPU::new(Hardware::new(vec![0xd5, 0xd6]).unwrap());
+        cpu.set_tracer(Some(Tracer::new(Box::new(Broken), Format::JsonLines)));
+        assert_eq!(cpu.run_for(2), StopReason::CycleBudget);
+        assert_eq!(cpu.tracer_mut().unwrap().error().unwrap().to_string(), "disk full");
+    }
+}